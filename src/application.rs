@@ -17,19 +17,36 @@ use ratatui::{
     }
 };
 use rand::seq::SliceRandom;
-use rand::rng;
+use rand::{rng, Rng};
 
-use crate::filemanager::find_smallest_unused_id;
-use crate::chromedriver::search_youtube;
-use crate::filemanager::Playlist;
+use crate::filemanager::{get_directory, scan_directory};
+use crate::chromedriver::SearchSession;
+use crate::filemanager::{Playlist, PlaylistInfo, SortOrder};
+use crate::cache::{SearchCache, SearchHistory};
+use crate::processing::Chapter;
 use crate::audio::AudioPlayer;
-use crate::downloader::Song;
+use crate::config::{self, get_config};
+use crate::doctor::{check, fix, Problem, Report};
+use crate::playlistfile;
+use crate::tags::clean_title;
+use crate::downloader::{is_youtube_url, resolve_url, DownloadManager, DownloadState, Song};
 
-#[derive(PartialEq, Eq)]
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const CONCURRENT_DOWNLOADS: usize = 3;
+// Unrated songs are shuffled as if they had this many stars.
+const UNRATED_WEIGHT: u8 = 3;
+const MOST_PLAYED_LENGTH: usize = 50;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
 pub enum ApplicationState {
     Homepage,
     Search,
-    Playlist
+    Playlist,
+    Playlists,
+    Doctor
 }
 
 #[derive(PartialEq, Eq)]
@@ -38,37 +55,169 @@ enum Mode {
     Input
 }
 
+/// What the text typed in input mode is for.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Prompt {
+    Search,
+    ImportDirectory,
+    NewPlaylist,
+    RenamePlaylist(i64),
+    DuplicatePlaylist(i64),
+    ExportPlaylist,
+    ImportPlaylist,
+    EditTitle,
+    EditArtist,
+    EditAlbum,
+    EditTags
+}
+
+impl Prompt {
+    fn label(&self) -> &'static str {
+        match self {
+            Prompt::Search => "",
+            Prompt::ImportDirectory => "Import directory: ",
+            Prompt::NewPlaylist => "New playlist: ",
+            Prompt::RenamePlaylist(_) => "Rename to: ",
+            Prompt::DuplicatePlaylist(_) => "Name of copy: ",
+            Prompt::ExportPlaylist => "Export to (.m3u8 or .xspf): ",
+            Prompt::ImportPlaylist => "Import playlist file: ",
+            Prompt::EditTitle => "Title: ",
+            Prompt::EditArtist => "Artist: ",
+            Prompt::EditAlbum => "Album: ",
+            Prompt::EditTags => "Tags (comma separated): "
+        }
+    }
+}
+
+/// A question shown in the status line, answered with y or n.
+enum Confirm {
+    Split(Song, Vec<Chapter>),
+    DeletePlaylist(i64, String),
+    DeleteSong(Song),
+    Fix(Problem),
+    /// Add every search result to the playlist, downloading them if true.
+    ImportResults(bool)
+}
+
+/// A pending confirmation. It only stands while the screen it was asked on and its question are still shown.
+struct Question {
+    confirm: Confirm,
+    state: ApplicationState,
+    text: String
+}
+
+/// Songs queued together by "download all missing", so a summary can be shown once they have all finished.
+struct DownloadBatch {
+    remaining: HashSet<String>,
+    total: usize,
+    downloaded: usize,
+    failed: usize
+}
+
+/// Paths typed by the user may start with ~/ for their home directory.
+fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => std::env::home_dir().unwrap_or_default().join(rest),
+        None => PathBuf::from(path)
+    }
+}
+
 pub struct Application {
     state: ApplicationState,
     mode: Mode,
+    prompt: Prompt,
     list_state: ListState,
 
     user_input: Vec<char>,
     playlist: Playlist,
     search_results: Vec<Song>,
+    search_session: Option<SearchSession>,
+    search_query: String,
+    search_cache: SearchCache,
+    search_history: SearchHistory,
+    more_results: bool,
+    /// True if the search results are the entries of a pasted playlist or channel URL rather than a text search.
+    resolved: bool,
+    status: String,
 
     running: bool,
-    audio_player: AudioPlayer
+    audio_player: AudioPlayer,
+    downloads: DownloadManager,
+    download_batch: Option<DownloadBatch>,
+    confirm: Option<Question>,
+    /// Downloaded songs with chapters, waiting to ask whether to split them.
+    splits: VecDeque<(Song, Vec<Chapter>)>,
+    playlists: Vec<PlaylistInfo>,
+    /// A search result being added to a playlist chosen in the picker, and the search selection to return to.
+    adding: Option<(Song, usize)>,
+    /// The song whose title, artist and album are being edited, one prompt after another.
+    editing: Option<Song>,
+    /// The order the playlist was last sorted into, so the next sort moves on to the following order.
+    sorted: Option<SortOrder>,
+    doctor: Option<Report>
 }
 
 impl Application {
-    pub fn new() -> Self {
-        Self {
+    /// Fails if the library cannot be loaded, before the terminal is taken over so the error can be printed.
+    pub fn new() -> Result<Self, String> {
+        let config_errors = config::init();
+
+        let mut application = Self {
             state: ApplicationState::Homepage,
             mode: Mode::Normal,
+            prompt: Prompt::Search,
             list_state: ListState::default(),
             user_input: Vec::new(),
-            playlist: Playlist::load_playlist().unwrap(),
+            playlist: Playlist::load_playlist()?,
             search_results: Vec::new(),
+            search_session: None,
+            search_query: String::new(),
+            search_cache: SearchCache::load(),
+            search_history: SearchHistory::load(),
+            more_results: false,
+            resolved: false,
+            status: config_errors.join("; "),
             running: true,
-            audio_player: AudioPlayer::new()
+            audio_player: AudioPlayer::new(),
+            downloads: DownloadManager::new(CONCURRENT_DOWNLOADS),
+            download_batch: None,
+            confirm: None,
+            splits: VecDeque::new(),
+            playlists: Vec::new(),
+            adding: None,
+            editing: None,
+            sorted: None,
+            doctor: None
+        };
+
+        if let Some(warning) = application.playlist.take_warning() {
+            if !application.status.is_empty() { application.status.push_str("; "); }
+            application.status.push_str(&warning);
         }
+        application.resume_downloads();
+        Ok(application)
     }
 
     pub async fn run(&mut self, terminal: &mut DefaultTerminal) {
         self.list_state.select(Some(0));
         while self.running {
+            self.poll_downloads();
+            self.check_confirm();
+            for song in self.audio_player.take_started() {
+                self.playlist.record_play(&song);
+            }
             let _ = terminal.draw(|frame| self.draw(frame));
+
+            // Redraw periodically so download progress updates without a key press.
+            match event::poll(Duration::from_millis(250)) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(_) => {
+                    self.running = false;
+                    return;
+                }
+            }
+
             let event = match event::read() {
                 Ok(e) => e,
                 Err(_) => {
@@ -77,29 +226,88 @@ impl Application {
                 }
             };
 
-            match event {
-                Event::Key(k) => self.handle_input(k).await,
-                _ => {}
+            if let Event::Key(k) = event {
+                self.handle_input(k).await;
             }
         }
+
+        if let Some(session) = self.search_session.take() {
+            session.quit().await;
+        }
     }
 
     async fn handle_input(&mut self, k: KeyEvent) {
         match k.code {
+            KeyCode::Char(c) if self.mode == Mode::Normal && self.confirm.is_some() && (c == 'y' || c == 'n') => {
+                if let Some(question) = self.confirm.take() {
+                    if c == 'y' {
+                        self.confirmed(question.confirm);
+                    } else {
+                        self.status.clear();
+                    }
+                }
+            }
+
+            KeyCode::Char(c) if self.mode == Mode::Input => self.user_input.push(c),
+
             KeyCode::Char(c) => {
                 match self.state {
                     ApplicationState::Search => {
-                        if self.mode == Mode::Input {
-                            self.user_input.push(c);
-                        } else {
-                            match c {
-                                'i' => self.mode = Mode::Input,
-                                'q' => self.running = false,
-                                'j' => self.list_state.select_next(),
-                                'k' => self.list_state.select_previous(),
-                                ' ' => self.audio_player.toggle(),
-                                 _  => {}
+                        match c {
+                            'i' => self.start_input(Prompt::Search),
+                            'q' => self.running = false,
+                            'j' => {
+                                if self.list_state.selected().is_some_and(|idx| idx + 1 >= self.search_results.len()) {
+                                    self.load_next_page().await;
+                                }
+                                self.list_state.select_next();
+                            }
+                            'k' => self.list_state.select_previous(),
+                            ' ' => self.audio_player.toggle(),
+                            'a' | 'd' => self.ask_import_search_results(c == 'd'),
+                            'p' => self.preview_search_option(),
+                            'l' => {
+                                let idx = match self.list_state.selected() {
+                                    Some(idx) if idx < self.search_results.len() => idx,
+                                    _ => return
+                                };
+                                self.adding = Some((self.search_results[idx].clone(), idx));
+                                self.show_playlists();
+                            }
+                             _  => {}
+                        }
+                    }
+
+                    ApplicationState::Doctor => {
+                        match c {
+                            'j' => self.list_state.select_next(),
+                            'k' => self.list_state.select_previous(),
+                            'q' => self.running = false,
+                            ' ' => self.audio_player.toggle(),
+                            _ => {}
+                        }
+                    }
+
+                    ApplicationState::Playlists => {
+                        match c {
+                            'j' => self.list_state.select_next(),
+                            'k' => self.list_state.select_previous(),
+                            'q' => self.running = false,
+                            ' ' => self.audio_player.toggle(),
+                            'n' => self.start_input(Prompt::NewPlaylist),
+                            'r' => {
+                                if let Some((id, name)) = self.selected_playlist() {
+                                    self.start_input(Prompt::RenamePlaylist(id));
+                                    self.user_input = name.chars().collect();
+                                }
                             }
+                            'c' => {
+                                if let Some((id, name)) = self.selected_playlist() {
+                                    self.start_input(Prompt::DuplicatePlaylist(id));
+                                    self.user_input = format!("{name} copy").chars().collect();
+                                }
+                            }
+                            _ => {}
                         }
                     }
 
@@ -120,54 +328,149 @@ impl Application {
                             'q' => self.running = false,
                             ' ' => self.audio_player.toggle(),
                             'a' => {
-                                let idx = match self.list_state.selected() {
-                                    Some(idx) => if idx < self.playlist.songs.len() { idx } else { return; },
+                                let idx = match self.selected_song() {
+                                    Some(idx) => idx,
                                     None => return
                                 };
                                 self.audio_player.append(self.playlist.songs[idx].clone());
                             }
+                            'c' => {
+                                let idx = match self.selected_song() {
+                                    Some(idx) => idx,
+                                    None => return
+                                };
+                                if self.downloads.cancel(&self.playlist.songs[idx].key()) {
+                                    self.status = format!("Cancelled download of {}", self.playlist.songs[idx].name);
+                                }
+                            }
+                            'd' => self.download_missing(),
+                            'J' | 'K' => {
+                                let idx = match self.selected_song() {
+                                    Some(idx) => idx,
+                                    None => return
+                                };
+                                if let Some(moved) = self.playlist.move_song(idx, c == 'K') {
+                                    self.list_state.select(Some(moved));
+                                }
+                            }
+                            's' => self.sort_playlist(),
+                            'E' => {
+                                let idx = match self.selected_song() {
+                                    Some(idx) => idx,
+                                    None => return
+                                };
+                                let song = self.playlist.songs[idx].clone();
+                                self.start_input(Prompt::EditTitle);
+                                self.user_input = song.name.chars().collect();
+                                self.editing = Some(song);
+                            }
+                            't' => {
+                                let idx = match self.selected_song() {
+                                    Some(idx) => idx,
+                                    None => return
+                                };
+                                let song = self.playlist.songs[idx].clone();
+                                self.clean_titles(&[song]);
+                            }
+                            'T' => self.clean_titles(&self.playlist.songs.clone()),
+                            '0'..='5' => {
+                                let idx = match self.selected_song() {
+                                    Some(idx) => idx,
+                                    None => return
+                                };
+                                // Pressing the rating a song already has clears it.
+                                let rating = c.to_digit(10).map(|rating| rating as u8).filter(|rating| *rating > 0 && self.playlist.songs[idx].rating != Some(*rating));
+                                let song = Song { rating, ..self.playlist.songs[idx].clone() };
+                                self.playlist.update_song(&song);
+                                self.status = match rating {
+                                    Some(1) => format!("Disliked {}", song.name),
+                                    Some(rating) => format!("Rated {} {} stars", song.name, rating),
+                                    None => format!("Cleared rating of {}", song.name)
+                                };
+                            }
+                            'g' => {
+                                let idx = match self.selected_song() {
+                                    Some(idx) => idx,
+                                    None => return
+                                };
+                                let song = self.playlist.songs[idx].clone();
+                                self.start_input(Prompt::EditTags);
+                                self.user_input = song.tags.join(", ").chars().collect();
+                                self.editing = Some(song);
+                            }
+                            'x' => {
+                                let idx = match self.selected_song() {
+                                    Some(idx) => idx,
+                                    None => return
+                                };
+                                let song = self.playlist.songs[idx].clone();
+                                self.ask(format!("Remove {} and delete its downloaded file? (y/n)", song.name), Confirm::DeleteSong(song));
+                            }
+                            'u' => {
+                                self.status = match self.playlist.undo_remove() {
+                                    Ok(song) => format!("Restored {}", song.name),
+                                    Err(e) => e
+                                };
+                            }
+                            'e' => {
+                                self.start_input(Prompt::ExportPlaylist);
+                                self.user_input = format!("~/{}.m3u8", self.playlist.name()).chars().collect();
+                            }
+                            'i' => self.start_input(Prompt::ImportPlaylist),
+                            'r' => {
+                                let retried = self.downloads.retry_failed();
+                                for song in &retried {
+                                    self.playlist.set_download_state(song, &DownloadState::Queued);
+                                }
+                                self.status = format!("Retrying {} failed downloads", retried.len());
+                            }
                             _ => {}
                         }
                     }
                 }
             }
 
+            KeyCode::Backspace if self.mode == Mode::Input => { self.user_input.pop(); },
+
             KeyCode::Backspace => {
                 match self.state {
                     ApplicationState::Homepage => {},
-                    ApplicationState::Search => {
-                        if self.mode == Mode::Input {
-                            self.user_input.pop();
-                        } else {
-                            self.state = ApplicationState::Homepage;
-                        }
+                    ApplicationState::Search => self.state = ApplicationState::Homepage,
+                    ApplicationState::Playlist => self.state = ApplicationState::Homepage,
+                    ApplicationState::Doctor => {
+                        self.doctor = None;
+                        self.state = ApplicationState::Homepage;
                     }
-                    ApplicationState::Playlist => {
-                        if self.mode == Mode::Input {
-                            self.user_input.pop();
-                        } else {
-                            self.state = ApplicationState::Homepage;
+                    ApplicationState::Playlists => match self.adding.take() {
+                        Some((_, idx)) => {
+                            self.state = ApplicationState::Search;
+                            self.list_state.select(Some(idx));
                         }
+                        None => self.state = ApplicationState::Homepage
                     }
                 }
             }
 
-            KeyCode::Delete => {
-                if self.state == ApplicationState::Playlist {
-                    match self.list_state.selected() {
-                        Some(idx) => if idx < self.playlist.songs.len() { self.playlist.remove_song(idx) },
-                        None => {}
+            KeyCode::Delete if self.state == ApplicationState::Playlists && self.adding.is_none() => {
+                if let Some((id, name)) = self.selected_playlist() {
+                    self.ask(format!("Delete playlist {name}? (y/n)"), Confirm::DeletePlaylist(id, name));
+                }
+            }
+
+            KeyCode::Delete if self.state == ApplicationState::Playlist => {
+                if let Some(song) = self.selected_song().map(|idx| self.playlist.songs[idx].clone()) {
+                    if self.remove_song(&song) {
+                        self.status = format!("Removed {} (u to undo)", song.name);
                     }
                 }
             }
 
+            KeyCode::Enter if self.mode == Mode::Input => self.submit_input().await,
+
             KeyCode::Enter => {
                 match self.state {
                     ApplicationState::Homepage => {
-                        let idx = match self.list_state.selected() {
-                            Some(n) => n,
-                            None => 0
-                        };
+                        let idx = self.list_state.selected().unwrap_or_default();
 
                         if idx == 0 {
                             self.state = ApplicationState::Search;
@@ -175,42 +478,61 @@ impl Application {
                         } else if idx == 1 {
                             self.state = ApplicationState::Playlist;
                             self.list_state.select(Some(0));
-                        } else {
+                        } else if idx == 2 {
+                            self.show_playlists();
+                        } else if idx == 3 {
                             self.shuffle();
+                        } else if idx == 4 {
+                            self.start_input(Prompt::ImportDirectory);
+                        } else if idx == 5 {
+                            self.play_most_played();
+                        } else {
+                            self.run_doctor();
                         }
                     }
 
                     ApplicationState::Playlist => {
-                        if self.mode == Mode::Normal {
-                            let idx = match self.list_state.selected() {
-                                Some(idx) => idx,
-                                None => return
-                            };
+                        let idx = match self.selected_song() {
+                            Some(idx) => idx,
+                            None => return
+                        };
 
-                            if idx < self.playlist.songs.len() {
-                                if self.playlist.songs[idx].file == None {
-                                    let i = find_smallest_unused_id(&self.playlist.songs).unwrap();
-                                    self.playlist.songs[idx].download(i);
-                                }
-                                else {
-                                    self.audio_player.play(self.playlist.songs[idx].clone());
-                                }
-                            }
+                        if self.playlist.songs[idx].file.is_none() {
+                            self.download_song(&self.playlist.songs[idx].clone());
+                        }
+                        else {
+                            self.audio_player.play(self.playlist.songs[idx].clone());
                         }
                     }
 
-                    ApplicationState::Search => {
-                        if self.mode == Mode::Input {
-                            let _ = self.mode == Mode::Normal;
-                            self.fill_search_criteria().await;
-                        } else {
-                            self.select_search_option();
+                    ApplicationState::Search => self.select_search_option(),
+
+                    ApplicationState::Playlists => self.open_selected_playlist(),
+
+                    ApplicationState::Doctor => {
+                        let problem = self.list_state.selected().and_then(|idx| self.doctor_lines().get(idx).and_then(|(problem, _)| *problem));
+                        if let (Some(problem), Some(report)) = (problem, self.doctor.as_ref()) {
+                            let question = format!("Fix {} ({}): {}? (y/n)", problem.label().to_lowercase(), report.count(problem), problem.fix());
+                            self.ask(question, Confirm::Fix(problem));
                         }
                     }
                 }
             }
 
-            KeyCode::Esc => { self.mode = Mode::Normal; self.user_input.clear(); },
+            KeyCode::Esc => { self.mode = Mode::Normal; self.user_input.clear(); self.search_history.reset(); },
+
+            KeyCode::Up if self.mode == Mode::Input && self.prompt == Prompt::Search => {
+                if let Some(query) = self.search_history.older() {
+                    self.user_input = query.chars().collect();
+                }
+            }
+
+            KeyCode::Down if self.mode == Mode::Input && self.prompt == Prompt::Search => {
+                self.user_input = match self.search_history.newer() {
+                    Some(query) => query.chars().collect(),
+                    None => Vec::new()
+                };
+            }
 
             KeyCode::Right => self.audio_player.skip(),
 
@@ -225,14 +547,19 @@ impl Application {
                     Constraint::Percentage(30)
             ]).split(frame.area());
         let block: Block = Block::bordered().border_set(border::ROUNDED).title_top(Line::from(match self.state {
-            ApplicationState::Search => "[ BROWSE SONGS ]",
-            ApplicationState::Homepage => "[ HOMEPAGE ]",
-            ApplicationState::Playlist => "[ SONGS ]",
-        }).centered().light_blue()).title_bottom(Line::from(format!("[ {} ]", self.user_input.iter().collect::<String>())).centered().white());
+            ApplicationState::Search => String::from("[ BROWSE SONGS ]"),
+            ApplicationState::Homepage => String::from("[ HOMEPAGE ]"),
+            ApplicationState::Playlist => format!("[ SONGS: {} ]", self.playlist.name()),
+            ApplicationState::Playlists => match self.adding {
+                Some((ref song, _)) => format!("[ ADD {} TO ]", song.name),
+                None => String::from("[ PLAYLISTS ]")
+            },
+            ApplicationState::Doctor => String::from("[ LIBRARY DOCTOR ]")
+        }).centered().light_blue()).title_bottom(Line::from(format!("[ {}{} ]", if self.mode == Mode::Input { self.prompt.label() } else { "" }, self.user_input.iter().collect::<String>())).centered().white());
 
         let lines: List = List::new(
             match self.state {
-                ApplicationState::Homepage => vec!["Browse Songs", "View Playlist", "Shuffle Play"].into_iter().map(|x| Line::from(x)).collect::<Vec<Line>>(),
+                ApplicationState::Homepage => vec!["Browse Songs", "View Playlist", "Playlists", "Shuffle Play", "Import Local Files", "Play Most Played", "Library Doctor"].into_iter().map(Line::from).collect::<Vec<Line>>(),
                 ApplicationState::Search => self.search_results.iter().map(|song| {
                     let line = Line::from(song.name.clone());
                     match self.playlist.contains(song) {
@@ -241,11 +568,36 @@ impl Application {
                     }
                 }).collect::<Vec<Line>>(),
                 ApplicationState::Playlist => self.playlist.songs.iter().map(|song| {
-                    let line = Line::from(song.name.clone());
+                    let progress = match self.downloads.state(&song.key()) {
+                        Some(DownloadState::Queued) => String::from(" [queued]"),
+                        Some(DownloadState::Downloading(percent)) => format!(" [{percent:.0}%]"),
+                        Some(DownloadState::Retrying(attempt)) => format!(" [retry {attempt}]"),
+                        Some(DownloadState::Failed(_)) => String::from(" [failed]"),
+                        Some(DownloadState::Done) | Some(DownloadState::Cancelled) | None => String::new()
+                    };
+                    let rating = match song.rating {
+                        Some(rating) => format!(" {}", "★".repeat(rating as usize)),
+                        None => String::new()
+                    };
+                    let tags = song.tags.iter().map(|tag| format!(" #{tag}")).collect::<String>();
+                    let line = Line::from(format!("{}{rating}{tags}{progress}", song.name));
                     match song.file {
                         Some(_) => line.white(),
                         None => line.gray()
                     }
+                }).collect::<Vec<Line>>(),
+                ApplicationState::Playlists => self.playlists.iter().map(|playlist| {
+                    let line = Line::from(format!("{} ({} songs)", playlist.name, playlist.songs));
+                    match playlist.id == self.playlist.id() {
+                        true => line.green(),
+                        false => line.white()
+                    }
+                }).collect::<Vec<Line>>(),
+                ApplicationState::Doctor => self.doctor_lines().into_iter().map(|(problem, text)| {
+                    match problem {
+                        Some(_) if !text.starts_with(' ') => Line::from(text).light_blue(),
+                        _ => Line::from(text).white()
+                    }
                 }).collect::<Vec<Line>>()
             }
         ).block(block).highlight_style(Style::new()).highlight_symbol("->");
@@ -256,14 +608,380 @@ impl Application {
             )).collect::<Vec<Line>>()
         ).block(
                 Block::bordered().border_set(border::ROUNDED).title("QUEUE".light_blue().into_centered_line())
+                    .title_bottom(Line::from(self.status.clone()).centered().gray())
             );
 
         frame.render_stateful_widget(lines, layout[0], &mut self.list_state);
         frame.render_widget(queue, layout[1]);
     }
 
+    fn start_input(&mut self, prompt: Prompt) {
+        self.prompt = prompt;
+        self.mode = Mode::Input;
+        self.user_input.clear();
+    }
+
+    async fn submit_input(&mut self) {
+        match self.prompt {
+            // The query stays in the input box so it can be refined.
+            Prompt::Search => self.fill_search_criteria().await,
+            Prompt::ImportDirectory => {
+                let directory = self.user_input.iter().collect::<String>();
+                self.mode = Mode::Normal;
+                self.user_input.clear();
+                self.import_directory(directory.trim());
+            }
+            Prompt::ExportPlaylist | Prompt::ImportPlaylist => {
+                let path = expand_home(self.user_input.iter().collect::<String>().trim());
+                self.mode = Mode::Normal;
+                self.user_input.clear();
+
+                self.status = match self.prompt {
+                    Prompt::ExportPlaylist => match playlistfile::export(&self.playlist.songs, self.playlist.name(), &path) {
+                        Ok(exported) => format!("Exported {exported} songs to {}", path.to_string_lossy()),
+                        Err(e) => e
+                    },
+                    _ => match playlistfile::import(&path) {
+                        Ok((songs, total)) => {
+                            let found = songs.len();
+                            let imported = self.playlist.import_songs(songs);
+                            format!("Imported {imported} of {total} entries ({} not found, {} already in playlist)", total - found, found - imported)
+                        }
+                        Err(e) => e
+                    }
+                };
+            }
+            Prompt::EditTitle | Prompt::EditArtist | Prompt::EditAlbum => {
+                let value = self.user_input.iter().collect::<String>().trim().to_string();
+                let song = match self.editing.as_mut() {
+                    Some(song) => song,
+                    None => return
+                };
+
+                // Each answer moves on to the next field, prefilled with its current value.
+                let next = match self.prompt {
+                    Prompt::EditTitle => {
+                        if !value.is_empty() { song.name = value; }
+                        Some((Prompt::EditArtist, song.channel.clone()))
+                    }
+                    Prompt::EditArtist => {
+                        song.channel = value;
+                        Some((Prompt::EditAlbum, song.album.clone()))
+                    }
+                    _ => {
+                        song.album = value;
+                        None
+                    }
+                };
+
+                match next {
+                    Some((prompt, current)) => {
+                        self.start_input(prompt);
+                        self.user_input = current.chars().collect();
+                    }
+                    None => {
+                        self.mode = Mode::Normal;
+                        self.user_input.clear();
+                        if let Some(song) = self.editing.take() {
+                            self.status = match self.playlist.edit_song(&song) {
+                                Ok(()) => format!("Saved {}", song.name),
+                                Err(e) => e
+                            };
+                        }
+                    }
+                }
+            }
+            Prompt::EditTags => {
+                let input = self.user_input.iter().collect::<String>();
+                self.mode = Mode::Normal;
+                self.user_input.clear();
+
+                if let Some(mut song) = self.editing.take() {
+                    song.tags.clear();
+                    for tag in input.split(',').map(|tag| tag.trim()).filter(|tag| !tag.is_empty()) {
+                        if !song.tags.iter().any(|existing| existing == tag) { song.tags.push(tag.to_string()); }
+                    }
+                    self.playlist.update_song(&song);
+                    self.status = format!("Set {} tags on {}", song.tags.len(), song.name);
+                }
+            }
+            Prompt::NewPlaylist | Prompt::RenamePlaylist(_) | Prompt::DuplicatePlaylist(_) => {
+                let name = self.user_input.iter().collect::<String>();
+                self.mode = Mode::Normal;
+                self.user_input.clear();
+
+                let result = match self.prompt {
+                    Prompt::RenamePlaylist(id) => self.playlist.rename_playlist(id, &name).map(|_| format!("Renamed playlist to {}", name.trim())),
+                    Prompt::DuplicatePlaylist(id) => self.playlist.duplicate_playlist(id, &name).map(|_| format!("Created playlist {}", name.trim())),
+                    _ => self.playlist.create_playlist(&name).map(|_| format!("Created playlist {}", name.trim()))
+                };
+                self.status = match result {
+                    Ok(message) => message,
+                    Err(e) => e
+                };
+                self.refresh_playlists();
+            }
+        }
+    }
+
+    /// Show a y/n question in the status line.
+    fn ask(&mut self, text: String, confirm: Confirm) {
+        self.status = text.clone();
+        self.confirm = Some(Question { confirm, state: self.state, text });
+    }
+
+    /// Drop a question once the user has moved to another screen or another message has replaced it,
+    /// so y and n go back to their usual bindings. Splits are asked again later on the playlist screen.
+    fn check_confirm(&mut self) {
+        if let Some(question) = self.confirm.take_if(|question| question.state != self.state || question.text != self.status) {
+            if let Confirm::Split(song, chapters) = question.confirm { self.splits.push_front((song, chapters)); }
+        }
+
+        if self.confirm.is_some() || self.state != ApplicationState::Playlist || self.mode != Mode::Normal { return; }
+        if let Some(idx) = self.splits.iter().position(|(song, _)| self.playlist.contains(song)) {
+            if let Some((song, chapters)) = self.splits.remove(idx) {
+                self.ask(format!("{} has {} chapters, split it into separate songs? (y/n)", song.name, chapters.len()), Confirm::Split(song, chapters));
+            }
+        }
+    }
+
+    fn confirmed(&mut self, confirm: Confirm) {
+        match confirm {
+            Confirm::Split(song, chapters) => {
+                self.status = match self.playlist.split_song(&song, &chapters) {
+                    Ok(()) => format!("Split {} into {} songs", song.name, chapters.len()),
+                    Err(e) => e
+                };
+            }
+            Confirm::DeleteSong(song) => {
+                if !self.remove_song(&song) { return; }
+                self.status = match self.playlist.delete_file(&song) {
+                    Ok(()) => format!("Removed {} and deleted its file (u to undo the removal)", song.name),
+                    Err(e) => format!("Removed {} but kept its file: {e} (u to undo)", song.name)
+                };
+            }
+            Confirm::Fix(problem) => {
+                let report = match self.doctor.take() {
+                    Some(report) => report,
+                    None => return
+                };
+                self.status = match fix(&mut self.playlist, &report, problem) {
+                    Ok(fixed) => format!("Fixed {fixed} of {}", report.count(problem)),
+                    Err(e) => e
+                };
+                self.doctor = Some(check(&self.playlist));
+            }
+            Confirm::ImportResults(download) => self.import_search_results(download),
+            Confirm::DeletePlaylist(id, name) => {
+                self.status = match self.playlist.delete_playlist(id) {
+                    Ok(()) => format!("Deleted playlist {name}"),
+                    Err(e) => e
+                };
+                self.refresh_playlists();
+            }
+        }
+    }
+
+    /// Check the library for problems and show them. Downloads write to the data directory,
+    /// so their files would look orphaned while they are still running.
+    fn run_doctor(&mut self) {
+        if self.downloads.active() > 0 {
+            self.status = String::from("Wait for downloads to finish before running the library doctor");
+            return;
+        }
+
+        self.doctor = Some(check(&self.playlist));
+        self.state = ApplicationState::Doctor;
+        self.list_state.select(Some(0));
+        self.status = String::from("Enter on a problem to fix it");
+    }
+
+    /// Each problem found by the doctor followed by what it affects, tagged with the problem for Enter to fix.
+    fn doctor_lines(&self) -> Vec<(Option<Problem>, String)> {
+        let report = match self.doctor {
+            Some(ref report) => report,
+            None => return Vec::new()
+        };
+        if report.is_empty() { return vec![(None, String::from("No problems found"))]; }
+
+        let mut lines: Vec<(Option<Problem>, String)> = Vec::new();
+        for problem in Problem::ALL {
+            if report.count(problem) == 0 { continue; }
+            lines.push((Some(problem), format!("{} ({})", problem.label(), report.count(problem))));
+            lines.extend(report.describe(problem).into_iter().map(|line| (Some(problem), format!("  {line}"))));
+        }
+        lines
+    }
+
+    /// Remove a song from the current playlist, cancelling its download if no other playlist has it.
+    fn remove_song(&mut self, song: &Song) -> bool {
+        match self.playlist.remove_song(song) {
+            Ok(()) => {
+                if !self.playlist.in_library(song) { self.downloads.cancel(&song.key()); }
+                true
+            }
+            Err(e) => {
+                self.status = e;
+                false
+            }
+        }
+    }
+
+    fn show_playlists(&mut self) {
+        self.state = ApplicationState::Playlists;
+        self.refresh_playlists();
+        let current = self.playlists.iter().position(|playlist| playlist.id == self.playlist.id());
+        self.list_state.select(Some(current.unwrap_or_default()));
+    }
+
+    fn refresh_playlists(&mut self) {
+        self.playlists = self.playlist.playlists();
+    }
+
+    /// Index of the selected song on the playlist screen.
+    fn selected_song(&self) -> Option<usize> {
+        self.list_state.selected().filter(|idx| *idx < self.playlist.songs.len())
+    }
+
+    fn selected_playlist(&self) -> Option<(i64, String)> {
+        let playlist = self.playlists.get(self.list_state.selected()?)?;
+        Some((playlist.id, playlist.name.clone()))
+    }
+
+    /// Open the playlist chosen in the picker, or add the song being added to it.
+    fn open_selected_playlist(&mut self) {
+        let (id, name) = match self.selected_playlist() {
+            Some(playlist) => playlist,
+            None => return
+        };
+
+        if let Some((song, idx)) = self.adding.take() {
+            self.status = match self.playlist.add_to_playlist(id, &song) {
+                Ok(true) => format!("Added {} to {name}", song.name),
+                Ok(false) => format!("{} is already in {name}", song.name),
+                Err(e) => e
+            };
+            self.state = ApplicationState::Search;
+            self.list_state.select(Some(idx));
+            return;
+        }
+
+        match self.playlist.switch_to(id) {
+            Ok(()) => {
+                self.state = ApplicationState::Playlist;
+                self.list_state.select(Some(0));
+                self.sorted = None;
+            }
+            Err(e) => self.status = e
+        }
+    }
+
+    /// Add every audio file under a directory to the playlist, using their tags for the song details.
+    fn import_directory(&mut self, directory: &str) {
+        let directory = expand_home(directory);
+
+        if !directory.is_dir() {
+            self.status = format!("{} is not a directory", directory.to_string_lossy());
+            return;
+        }
+
+        let songs = scan_directory(&directory);
+        let found = songs.len();
+        let imported = self.playlist.import_songs(songs);
+        self.status = format!("Imported {imported} of {found} audio files from {}", directory.to_string_lossy());
+    }
+
     async fn fill_search_criteria(&mut self) {
-        self.search_results = search_youtube(self.user_input.iter().collect::<String>()).await.unwrap();
+        let query = self.user_input.iter().collect::<String>();
+        self.search_history.push(&query);
+        self.search_query = query.clone();
+        self.resolved = is_youtube_url(&query);
+
+        if let Some(results) = self.search_cache.get(&query) {
+            self.more_results = !is_youtube_url(&query);
+            self.show_search_results(results);
+            return;
+        }
+
+        let results = if is_youtube_url(&query) {
+            self.more_results = false;
+            resolve_url(&query).await
+        } else {
+            match self.search_session().await {
+                Ok(session) => session.search(&query).await,
+                Err(e) => Err(e)
+            }
+        };
+
+        match results {
+            Ok(results) => {
+                if !is_youtube_url(&query) { self.more_results = !results.is_empty(); }
+                self.search_cache.store(&query, &results);
+                self.show_search_results(results);
+            }
+            Err(e) => match self.search_cache.get_stale(&query) {
+                Some((results, age)) => {
+                    self.more_results = false;
+                    self.show_search_results(results);
+                    self.status = format!("Offline, showing results from {} hours ago", age / 3600);
+                }
+                None => self.status = e
+            }
+        }
+    }
+
+    fn show_search_results(&mut self, results: Vec<Song>) {
+        self.search_results = results;
+        self.list_state.select(Some(0));
+        self.status.clear();
+    }
+
+    /// The running search session, launching chromedriver if it is not running yet.
+    async fn search_session(&mut self) -> Result<&mut SearchSession, String> {
+        if self.search_session.is_none() {
+            self.search_session = Some(SearchSession::new().await?);
+        }
+
+        match self.search_session.as_mut() {
+            Some(session) => Ok(session),
+            None => Err(String::from("Search session is not running."))
+        }
+    }
+
+    async fn load_next_page(&mut self) {
+        if !self.more_results { return; }
+        let query = self.search_query.clone();
+
+        let session = match self.search_session().await {
+            Ok(session) => session,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+
+        // Results shown from the cache have no page loaded behind them yet.
+        let page = if session.query() == Some(query.as_str()) {
+            session.next_page().await
+        } else {
+            match session.search(&query).await {
+                Ok(mut results) => {
+                    if let Ok(more) = session.next_page().await { results.extend(more); }
+                    Ok(results)
+                }
+                Err(e) => Err(e)
+            }
+        };
+
+        match page {
+            Ok(results) => {
+                let new_results = results.into_iter().filter(|song| !self.search_results.iter().any(|s| s.url == song.url)).collect::<Vec<Song>>();
+                self.more_results = !new_results.is_empty();
+                self.search_results.extend(new_results);
+                self.search_cache.store(&query, &self.search_results);
+            }
+            Err(e) => self.status = e
+        }
     }
 
     fn select_search_option(&mut self) {
@@ -272,17 +990,202 @@ impl Application {
         }
 
         let idx = match self.list_state.selected() {
-            Some(idx) => if idx < self.search_results.len() { idx } else { return; },
-            None => return
+            Some(idx) if idx < self.search_results.len() => idx,
+            _ => return
         };
 
         if !self.playlist.contains(&self.search_results[idx]) { self.playlist.add_song(self.search_results[idx].clone()); }
     }
 
+    /// Stream the selected search result without adding it to the playlist.
+    fn preview_search_option(&mut self) {
+        let idx = match self.list_state.selected() {
+            Some(idx) if idx < self.search_results.len() => idx,
+            _ => return
+        };
+
+        self.status = format!("Previewing {}", self.search_results[idx].name);
+        self.audio_player.play(self.search_results[idx].clone());
+    }
+
+    /// Bulk import the entries of a resolved playlist or channel straight away, but ask first for the results
+    /// of a text search, which are rarely all wanted.
+    fn ask_import_search_results(&mut self, download: bool) {
+        if self.search_results.is_empty() { return; }
+        if self.resolved {
+            self.import_search_results(download);
+            return;
+        }
+
+        let action = if download { "add and download" } else { "add" };
+        self.ask(format!("This is a search, not a playlist: {action} all {} results? (y/n)", self.search_results.len()), Confirm::ImportResults(download));
+    }
+
+    /// Add every search result (e.g. all entries of a resolved playlist) that is not already
+    /// in the playlist, optionally starting a download for each one.
+    fn import_search_results(&mut self, download: bool) {
+        let new_songs = self.search_results.iter().filter(|song| !self.playlist.contains(song)).cloned().collect::<Vec<Song>>();
+        let skipped = self.search_results.len() - new_songs.len();
+        let first = self.playlist.songs.len();
+        let imported = self.playlist.import_songs(new_songs);
+
+        if download {
+            let new_songs = self.playlist.songs[first..].to_vec();
+            for song in &new_songs {
+                self.download_song(song);
+            }
+        }
+
+        self.status = format!("Imported {imported} songs ({skipped} already in playlist)");
+    }
+
+    fn download_song(&mut self, song: &Song) -> bool {
+        if song.url.is_none() { return false; }
+
+        let output = get_directory().join(format!("{}.mp3", song.file_id()));
+        self.downloads.queue(song.clone(), output);
+        self.playlist.set_download_state(song, &DownloadState::Queued);
+        true
+    }
+
+    /// Queue the downloads that were still waiting when the app was last closed.
+    fn resume_downloads(&mut self) {
+        let interrupted = self.playlist.interrupted_downloads();
+        if interrupted.is_empty() { return; }
+
+        for song in &interrupted {
+            self.download_song(song);
+        }
+        if self.status.is_empty() {
+            self.status = format!("Resuming {} interrupted downloads", interrupted.len());
+        }
+    }
+
+    /// Queue a download for every song in the playlist that has not been downloaded yet.
+    fn download_missing(&mut self) {
+        let (downloading, mut missing): (Vec<Song>, Vec<Song>) = self.playlist.not_downloaded().into_iter()
+            .partition(|song| self.downloads.state(&song.key()).is_some_and(|state| !state.is_finished()));
+
+        if missing.is_empty() {
+            self.status = match downloading.len() {
+                0 => String::from("Every song is already downloaded"),
+                n => format!("{n} missing songs are already downloading")
+            };
+            return;
+        }
+
+        missing.retain(|song| self.download_song(song));
+        if missing.is_empty() { return; }
+
+        // A batch still running takes in the new songs, so its summary covers both presses.
+        let batch = self.download_batch.get_or_insert_with(|| DownloadBatch {
+            remaining: HashSet::new(),
+            total: 0,
+            downloaded: 0,
+            failed: 0
+        });
+        for song in &missing {
+            if batch.remaining.insert(song.key()) { batch.total += 1; }
+        }
+        self.status = format!("Downloading {} missing songs", missing.len());
+    }
+
+    fn poll_downloads(&mut self) {
+        for (song, chapters, state) in self.downloads.poll() {
+            self.playlist.set_download_state(&song, &state);
+            let key = song.key();
+            let name = song.name.clone();
+
+            if let Some(batch) = self.download_batch.as_mut() {
+                if batch.remaining.remove(&key) {
+                    match state {
+                        DownloadState::Done => batch.downloaded += 1,
+                        _ => batch.failed += 1
+                    }
+                }
+            }
+
+            match state {
+                DownloadState::Done => {
+                    let song = self.playlist.set_downloaded(&song).unwrap_or(song);
+                    self.status = format!("Downloaded {}", song.name);
+                    if !chapters.is_empty() { self.splits.push_back((song, chapters)); }
+                }
+                DownloadState::Failed(e) => self.status = format!("Failed to download {name}: {e}"),
+                _ => {}
+            }
+        }
+
+        if let Some(batch) = self.download_batch.take_if(|batch| batch.remaining.is_empty()) {
+            self.status = format!("Downloaded {} of {} missing songs ({} failed)", batch.downloaded, batch.total, batch.failed);
+        }
+    }
+
+    /// Sort the playlist, cycling through the sort orders on each press.
+    fn sort_playlist(&mut self) {
+        let order = match self.sorted.and_then(|sorted| SortOrder::ALL.iter().position(|order| *order == sorted)) {
+            Some(idx) => SortOrder::ALL[(idx + 1) % SortOrder::ALL.len()],
+            None => SortOrder::ALL[0]
+        };
+
+        self.status = match self.playlist.sort(order) {
+            Ok(()) => format!("Sorted by {} (s for the next order)", order.label()),
+            Err(e) => e
+        };
+        self.sorted = Some(order);
+        self.list_state.select(Some(0));
+    }
+
+    /// Strip upload noise like "(Official Video)" from the titles of the given songs.
+    fn clean_titles(&mut self, songs: &[Song]) {
+        let mut cleaned = 0;
+        let mut errors: Vec<String> = Vec::new();
+        for song in songs {
+            let name = clean_title(&song.name);
+            if name.is_empty() || name == song.name { continue; }
+
+            match self.playlist.edit_song(&Song { name, ..song.clone() }) {
+                Ok(()) => cleaned += 1,
+                Err(e) => errors.push(e)
+            }
+        }
+
+        self.status = match errors.first() {
+            Some(e) => format!("Cleaned {cleaned} titles ({} failed: {e})", errors.len()),
+            None if cleaned == 0 => String::from("No titles needed cleaning"),
+            None => format!("Cleaned {cleaned} titles")
+        };
+    }
+
+    /// Queue the most played downloaded songs, most played first.
+    fn play_most_played(&mut self) {
+        let songs = self.playlist.most_played(MOST_PLAYED_LENGTH).into_iter().filter(|song| song.file.is_some()).collect::<Vec<Song>>();
+        if songs.is_empty() {
+            self.status = String::from("Nothing has been played yet");
+            return;
+        }
+
+        for song in songs {
+            self.audio_player.append(song);
+        }
+    }
+
     fn shuffle(&mut self) {
+        let shuffle = &get_config().shuffle;
         let mut rng = rng();
-        let mut songs: Vec<Song> = self.playlist.songs.iter().filter(|x| x.file != None).map(|x| x.clone()).collect();
-        songs.shuffle(&mut rng);
+        let mut songs: Vec<Song> = self.playlist.songs.iter().filter(|x| x.file.is_some() && !(shuffle.skip_disliked && x.disliked())).cloned().collect();
+        match shuffle.weight_by_rating {
+            // Weighted random order: each song is sorted by a random key raised to 1 / its weight,
+            // so higher rated songs tend to come first without always doing so.
+            true => {
+                let mut keyed = songs.into_iter()
+                    .map(|song| (rng.random::<f64>().powf(1.0 / song.rating.unwrap_or(UNRATED_WEIGHT) as f64), song))
+                    .collect::<Vec<(f64, Song)>>();
+                keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+                songs = keyed.into_iter().map(|(_, song)| song).collect();
+            }
+            false => songs.shuffle(&mut rng)
+        }
         for song in &songs {
             self.audio_player.append(song.clone());
         }
@@ -1,9 +1,12 @@
 use std::fs::File;
-use std::io::BufReader;
-use rodio::Sink;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::process::{Child, Command, Stdio};
+use rodio::source::from_iter;
+use rodio::{Sink, Source};
 use rodio::{Decoder, OutputStream, OutputStreamHandle};
 use std::sync::{
     Arc,
+    Condvar,
     Mutex
 };
 use std::time::Duration;
@@ -13,8 +16,9 @@ use std::thread::{
     spawn
 };
 
+use crate::config::get_config;
 use crate::downloader::Song;
-type AMV<T> = Arc<Mutex<Vec<T>>>;
+type Amv<T> = Arc<Mutex<Vec<T>>>;
 type AM<T> = Arc<Mutex<T>>;
 
 fn sync<T>(obj: T) -> AM<T> { Arc::new(Mutex::new(obj)) }
@@ -23,9 +27,10 @@ pub struct AudioPlayer {
     _stream: OutputStream,
     _stream_handle: OutputStreamHandle,
     sink: AM<Sink>,
-    playlist: AMV<Song>,
+    playlist: Amv<Song>,
     _poll_handle: JoinHandle<()>,
-    queue_sink_clear: AM<bool>
+    queue_sink_clear: AM<bool>,
+    started: Amv<Song>
 }
 
 impl AudioPlayer {
@@ -33,14 +38,16 @@ impl AudioPlayer {
         let (stream, stream_handle) = OutputStream::try_default().unwrap();
         let sink = sync(Sink::try_new(&stream_handle).unwrap());
 
-        let playlist: AMV<Song> = sync(Vec::new());
+        let playlist: Amv<Song> = sync(Vec::new());
         let polling_sink = Arc::clone(&sink);
         let polling_playlist = Arc::clone(&playlist);
         let queue_clear = sync(false);
         let polling_clear = Arc::clone(&queue_clear);
+        let started = sync(Vec::new());
+        let polling_started = Arc::clone(&started);
 
         let _poll_handle = spawn(move || {
-            manage_queue(polling_playlist, polling_sink, polling_clear);
+            manage_queue(polling_playlist, polling_sink, polling_clear, polling_started);
         });
         
         Self {
@@ -49,7 +56,8 @@ impl AudioPlayer {
             sink,
             playlist,
             _poll_handle,
-            queue_sink_clear: queue_clear
+            queue_sink_clear: queue_clear,
+            started
         }
     }
 
@@ -90,7 +98,7 @@ impl AudioPlayer {
         let sink = self.sink.lock().unwrap();
         let mut queue = self.playlist.lock().unwrap();
 
-        if queue.len() > 0 {
+        if !queue.is_empty() {
             queue.remove(0);
         }
         sink.skip_one();
@@ -100,30 +108,197 @@ impl AudioPlayer {
         let mut queue = self.playlist.lock().unwrap();
         queue.push(song.clone());
     }
+
+    /// Songs that have started playing since the last call.
+    pub fn take_started(&mut self) -> Vec<Song> {
+        let mut started = self.started.lock().unwrap();
+        std::mem::take(&mut *started)
+    }
 }
 
-pub fn manage_queue(queue: AMV<Song>, sink: AM<Sink>, clear: AM<bool>) {
+type BoxedSource = Box<dyn Source<Item = i16> + Send>;
+
+pub fn manage_queue(queue: Amv<Song>, sink: AM<Sink>, clear: AM<bool>, started: Amv<Song>) {
     loop {
         sleep(Duration::from_secs(1));
-        {
+        let next = {
             let sink = sink.lock().unwrap();
-            let mut c = clear.lock().unwrap();
+            {
+                let mut c = clear.lock().unwrap();
+                if *c {
+                    sink.clear();
+                    *c = false;
+                    sink.play();
+                }
+            }
 
-            if *c {
-                sink.clear();
-                *c = false;
-                sink.play();
+            if !sink.empty() {
+                continue;
             }
 
-            if sink.empty() {
+            let queue = queue.lock().unwrap();
+            match queue.first() {
+                Some(song) => song.clone(),
+                None => continue
+            }
+        };
+
+        // Opening a stream can take a few seconds, so do it without holding any locks.
+        match open_source(&next) {
+            Ok(source) => {
+                let sink = sink.lock().unwrap();
                 let queue = queue.lock().unwrap();
-                if queue.len() < 1 {
-                    continue;
+                if queue.first() == Some(&next) && !*clear.lock().unwrap() {
+                    sink.append(source);
+                    started.lock().unwrap().push(next);
                 }
-                let file = BufReader::new(File::open(queue[0].file.clone().unwrap()).unwrap());
-                let source = Decoder::new(file).unwrap();
-                sink.append(source);
             }
+            Err(_) => {
+                let mut queue = queue.lock().unwrap();
+                if queue.first() == Some(&next) { queue.remove(0); }
+            }
+        }
+    }
+}
+
+/// Decode a downloaded song from disk, or stream it from its URL if it has not been downloaded.
+fn open_source(song: &Song) -> Result<BoxedSource, String> {
+    match song.file {
+        Some(ref path) => {
+            // Each range gets its own decoder, played back to back.
+            let mut ranges: Vec<BoxedSource> = Vec::new();
+            for (start, end) in song.play_ranges() {
+                let file = BufReader::new(File::open(path).map_err(|e| format!("{e:?}"))?);
+                let mut decoder = Decoder::new(file).map_err(|e| format!("{e:?}"))?;
+                // Seeking jumps straight to the range; only a decoder that cannot seek decodes its way there.
+                let source: BoxedSource = match decoder.try_seek(start) {
+                    Ok(()) => Box::new(decoder),
+                    Err(_) => Box::new(decoder.skip_duration(start))
+                };
+                ranges.push(match end {
+                    Some(end) => Box::new(source.take_duration(end.saturating_sub(start))),
+                    None => Box::new(source)
+                });
+            }
+            Ok(Box::new(from_iter(ranges)))
+        }
+        None => {
+            let url = match song.url {
+                Some(ref url) => url,
+                None => return Err(String::from("Song has neither a file nor a URL"))
+            };
+            let source = Decoder::new_mp3(StreamBuffer::spawn(url)?).map_err(|e| format!("{e:?}"))?;
+            Ok(Box::new(source))
+        }
+    }
+}
+
+struct StreamState {
+    bytes: Vec<u8>,
+    finished: bool
+}
+
+/// Audio arriving through a pipe, buffered as it arrives so the decoder can seek backwards
+/// while probing. Reads block until enough data has been received or the stream ends.
+struct StreamBuffer {
+    state: Arc<(Mutex<StreamState>, Condvar)>,
+    children: Vec<Child>,
+    position: u64
+}
+
+impl StreamBuffer {
+    /// yt-dlp -f bestaudio -o - <url> | ffmpeg -i pipe:0 -f mp3 pipe:1
+    fn spawn(url: &str) -> Result<Self, String> {
+        let mut ytdlp = get_config().yt_dlp.command().arg("-q").arg("-f").arg("bestaudio").arg("-o").arg("-").arg(url)
+            .stdout(Stdio::piped()).stderr(Stdio::null()).spawn().map_err(|e| format!("Failed to spawn yt-dlp: {e:?}"))?;
+        let ytdlp_out = match ytdlp.stdout.take() {
+            Some(stdout) => stdout,
+            None => return Err(String::from("Failed to capture STDOUT of yt-dlp."))
+        };
+
+        let mut ffmpeg = match Command::new("ffmpeg").arg("-loglevel").arg("quiet").arg("-i").arg("pipe:0").arg("-f").arg("mp3").arg("pipe:1")
+            .stdin(Stdio::from(ytdlp_out)).stdout(Stdio::piped()).stderr(Stdio::null()).spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                let _ = ytdlp.kill();
+                return Err(format!("Failed to spawn ffmpeg: {e:?}"));
+            }
+        };
+        let mut stdout = match ffmpeg.stdout.take() {
+            Some(stdout) => stdout,
+            None => return Err(String::from("Failed to capture STDOUT of ffmpeg."))
+        };
+
+        let state = Arc::new((Mutex::new(StreamState { bytes: Vec::new(), finished: false }), Condvar::new()));
+        let writer = Arc::clone(&state);
+        spawn(move || {
+            let mut chunk = [0u8; 16384];
+            loop {
+                let read = stdout.read(&mut chunk).unwrap_or(0);
+                let (lock, signal) = &*writer;
+                let mut state = lock.lock().unwrap();
+                if read == 0 {
+                    state.finished = true;
+                    signal.notify_all();
+                    return;
+                }
+                state.bytes.extend_from_slice(&chunk[..read]);
+                signal.notify_all();
+            }
+        });
+
+        Ok(Self {
+            state,
+            children: vec![ytdlp, ffmpeg],
+            position: 0
+        })
+    }
+}
+
+impl Read for StreamBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let (lock, signal) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while state.bytes.len() as u64 <= self.position && !state.finished {
+            state = signal.wait(state).unwrap();
+        }
+
+        let start = (self.position as usize).min(state.bytes.len());
+        let count = buf.len().min(state.bytes.len() - start);
+        buf[..count].copy_from_slice(&state.bytes[start..start + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for StreamBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+            SeekFrom::End(offset) => {
+                let (lock, signal) = &*self.state;
+                let mut state = lock.lock().unwrap();
+                while !state.finished {
+                    state = signal.wait(state).unwrap();
+                }
+                state.bytes.len() as i64 + offset
+            }
+        };
+
+        if position < 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start of stream"));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl Drop for StreamBuffer {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
         }
     }
 }
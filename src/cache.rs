@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::time::Duration;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::filemanager::{get_directory, now, write_atomic};
+use crate::downloader::Song;
+
+const SEARCH_CACHE_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+const HISTORY_LENGTH: usize = 100;
+
+fn normalise(query: &str) -> String {
+    query.trim().to_lowercase()
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedSearch {
+    fetched: i64,
+    results: Vec<Song>
+}
+
+/// Search results keyed by query, persisted so repeated searches are instant and work offline.
+pub struct SearchCache {
+    entries: HashMap<String, CachedSearch>,
+    file: PathBuf
+}
+
+impl SearchCache {
+    pub fn load() -> Self {
+        let file = get_directory().join("search_cache.json");
+        let entries = match read_to_string(&file) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
+            Err(_) => HashMap::new()
+        };
+
+        Self { entries, file }
+    }
+
+    /// Results for the query if they were fetched within the TTL.
+    pub fn get(&self, query: &str) -> Option<Vec<Song>> {
+        let entry = self.entries.get(&normalise(query))?;
+        if now() - entry.fetched > SEARCH_CACHE_TTL.as_secs() as i64 { return None; }
+        Some(entry.results.clone())
+    }
+
+    /// Results for the query regardless of age, along with how many seconds old they are.
+    pub fn get_stale(&self, query: &str) -> Option<(Vec<Song>, i64)> {
+        let entry = self.entries.get(&normalise(query))?;
+        Some((entry.results.clone(), now() - entry.fetched))
+    }
+
+    pub fn store(&mut self, query: &str, results: &[Song]) {
+        let expired = now() - SEARCH_CACHE_TTL.as_secs() as i64 * 7;
+        self.entries.retain(|_, entry| entry.fetched > expired);
+        self.entries.insert(normalise(query), CachedSearch { fetched: now(), results: results.to_vec() });
+
+        if let Ok(serial) = serde_json::to_string(&self.entries) {
+            let _ = write_atomic(&self.file, serial.as_bytes());
+        }
+    }
+}
+
+/// Previously submitted queries, oldest first, with a cursor for up/down navigation.
+pub struct SearchHistory {
+    queries: Vec<String>,
+    cursor: Option<usize>,
+    file: PathBuf
+}
+
+impl SearchHistory {
+    pub fn load() -> Self {
+        let file = get_directory().join("search_history.txt");
+        let queries = match read_to_string(&file) {
+            Ok(contents) => contents.lines().map(|x| x.to_string()).collect::<Vec<String>>(),
+            Err(_) => vec![]
+        };
+
+        Self { queries, cursor: None, file }
+    }
+
+    pub fn push(&mut self, query: &str) {
+        let query = query.trim().to_string();
+        self.cursor = None;
+        if query.is_empty() { return; }
+
+        self.queries.retain(|q| *q != query);
+        self.queries.push(query);
+        if self.queries.len() > HISTORY_LENGTH {
+            self.queries.remove(0);
+        }
+
+        let contents = self.queries.iter().map(|query| format!("{query}\n")).collect::<String>();
+        let _ = write_atomic(&self.file, contents.as_bytes());
+    }
+
+    /// Step back to an older query.
+    pub fn older(&mut self) -> Option<String> {
+        let cursor = match self.cursor {
+            Some(0) => 0,
+            Some(c) => c - 1,
+            None => self.queries.len().checked_sub(1)?
+        };
+        self.cursor = Some(cursor);
+        self.queries.get(cursor).cloned()
+    }
+
+    /// Step forward to a newer query, returning None once past the newest.
+    pub fn newer(&mut self) -> Option<String> {
+        let cursor = self.cursor? + 1;
+        if cursor >= self.queries.len() {
+            self.cursor = None;
+            return None;
+        }
+        self.cursor = Some(cursor);
+        self.queries.get(cursor).cloned()
+    }
+
+    pub fn reset(&mut self) {
+        self.cursor = None;
+    }
+}
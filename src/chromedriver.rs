@@ -1,62 +1,162 @@
 use tokio::io::{BufReader, AsyncBufReadExt};
-use tokio::process::Command;
+use tokio::process::{Child, Command};
+use tokio::time::sleep;
 use std::process::Stdio;
+use std::time::Duration;
 use thirtyfour::prelude::*;
 
 use crate::downloader::Song;
 
-pub async fn search_youtube(query: String) -> Result<Vec<Song>, String> {
-    let mut chromedriver = match Command::new("chromedriver").stdout(Stdio::piped()).spawn() {
-        Ok(child) => child,
-        Err(e) => return Err(format!("Failed to spawn chromedriver: {e:?}"))
-    };
-
-    let stdout = match chromedriver.stdout.take() {
-        Some(stdout) => stdout,
-        None => return Err(String::from("Failed to capture STDOUT of chromedriver."))
-    };
-
-    let mut reader = BufReader::new(stdout).lines();
-
-    for _ in 0..3 { let _ = reader.next_line().await; }
-
-    let ip = match reader.next_line().await {
-        Ok(line) => line.unwrap().split(" ").nth(6).unwrap().to_string().strip_suffix('.').unwrap().to_string(),
-        Err(e) => return Err(format!("Failed to read STDOUT of chromedriver: {e:?}"))
-    };
-
-    let mut caps = DesiredCapabilities::chrome();
-    let _ = caps.add_arg("--headless");
-    let _ = caps.add_arg("--disable-gpu");
-    let _ = caps.add_arg("--no-sandbox");
-
-    let driver = WebDriver::new(format!("http://localhost:{ip}"), caps).await.unwrap();
-
-    driver.goto(format!("https://youtube.com/results?search_query={}", query)).await.unwrap();
-    let video_titles = driver.find_all(By::Id("video-title")).await.unwrap();
-    let video_channels = driver.find_all(By::Id("channel-name")).await.unwrap();
-
-    let mut options: Vec<Song> = Vec::new();
-
-    for idx in 0..video_titles.len() {
-        let title = video_titles[idx].text().await.unwrap();
-        let url_idx = video_titles[idx].outer_html().await.unwrap().find("href").unwrap();
-        let url_slice = &video_titles[idx].outer_html().await.unwrap().to_string()[url_idx..];
-        let url_end = match url_slice.find("&") {
-            Some(idx) => idx,
-            None => continue
-        } + url_idx;
-        let url_slice = String::from("https://youtube.com/") + &video_titles[idx].outer_html().await.unwrap().to_string()[url_idx + 7..url_end];
-        let channel = video_channels[idx * 2 + 1].text().await.unwrap();
-
-        options.push(Song {
-            name: title,
-            channel,
-            url: url_slice,
-            file: None
-        });
+// How many times to poll for new results after scrolling before giving up.
+const PAGE_LOAD_ATTEMPTS: usize = 20;
+
+pub struct SearchSession {
+    chromedriver: Child,
+    driver: WebDriver,
+    query: Option<String>,
+    seen: usize
+}
+
+impl SearchSession {
+    pub async fn new() -> Result<Self, String> {
+        let mut chromedriver = match Command::new("chromedriver").stdout(Stdio::piped()).kill_on_drop(true).spawn() {
+            Ok(child) => child,
+            Err(e) => return Err(format!("Failed to spawn chromedriver: {e:?}"))
+        };
+
+        let stdout = match chromedriver.stdout.take() {
+            Some(stdout) => stdout,
+            None => return Err(String::from("Failed to capture STDOUT of chromedriver."))
+        };
+
+        let mut reader = BufReader::new(stdout).lines();
+
+        for _ in 0..3 { let _ = reader.next_line().await; }
+
+        let ip = match reader.next_line().await {
+            Ok(Some(line)) => match line.split(" ").nth(6).and_then(|port| port.strip_suffix('.')) {
+                Some(port) => port.to_string(),
+                None => return Err(format!("Unexpected chromedriver output: {line}"))
+            },
+            Ok(None) => return Err(String::from("chromedriver exited before reporting its port.")),
+            Err(e) => return Err(format!("Failed to read STDOUT of chromedriver: {e:?}"))
+        };
+
+        let mut caps = DesiredCapabilities::chrome();
+        let _ = caps.add_arg("--headless");
+        let _ = caps.add_arg("--disable-gpu");
+        let _ = caps.add_arg("--no-sandbox");
+
+        let driver = match WebDriver::new(format!("http://localhost:{ip}"), caps).await {
+            Ok(driver) => driver,
+            Err(e) => return Err(format!("Failed to connect to chromedriver: {e:?}"))
+        };
+
+        Ok(Self {
+            chromedriver,
+            driver,
+            query: None,
+            seen: 0
+        })
+    }
+
+    pub async fn search(&mut self, query: &str) -> Result<Vec<Song>, String> {
+        if let Err(e) = self.driver.goto(format!("https://youtube.com/results?search_query={}", encode_query(query))).await {
+            return Err(format!("Failed to load search results: {e:?}"));
+        }
+
+        self.query = Some(query.to_string());
+        self.seen = 0;
+        self.collect_results().await
+    }
+
+    /// The query whose results page is currently loaded, if any.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
     }
 
-    driver.quit().await.unwrap();
-    Ok(options)
+    /// Scroll to the bottom of the results page so YouTube loads the next continuation,
+    /// then return only the songs that were not returned before.
+    pub async fn next_page(&mut self) -> Result<Vec<Song>, String> {
+        if let Err(e) = self.driver.execute("window.scrollTo(0, document.documentElement.scrollHeight);", Vec::new()).await {
+            return Err(format!("Failed to scroll search results: {e:?}"));
+        }
+
+        for _ in 0..PAGE_LOAD_ATTEMPTS {
+            sleep(Duration::from_millis(250)).await;
+            let loaded = match self.driver.find_all(By::Id("video-title")).await {
+                Ok(titles) => titles.len(),
+                Err(_) => continue
+            };
+            if loaded > self.seen { break; }
+        }
+
+        self.collect_results().await
+    }
+
+    pub async fn quit(mut self) {
+        let _ = self.driver.quit().await;
+        let _ = self.chromedriver.kill().await;
+    }
+
+    async fn collect_results(&mut self) -> Result<Vec<Song>, String> {
+        let video_titles = match self.driver.find_all(By::Id("video-title")).await {
+            Ok(titles) => titles,
+            Err(e) => return Err(format!("Failed to find search results: {e:?}"))
+        };
+        let video_channels = match self.driver.find_all(By::Id("channel-name")).await {
+            Ok(channels) => channels,
+            Err(e) => return Err(format!("Failed to find search result channels: {e:?}"))
+        };
+
+        let mut options: Vec<Song> = Vec::new();
+
+        for (idx, video_title) in video_titles.iter().enumerate().skip(self.seen) {
+            let title = match video_title.text().await {
+                Ok(title) => title,
+                Err(_) => continue
+            };
+            let html = match video_title.outer_html().await {
+                Ok(html) => html,
+                Err(_) => continue
+            };
+            let url_idx = match html.find("href") {
+                Some(idx) => idx,
+                None => continue
+            };
+            let url_end = match html[url_idx..].find("&") {
+                Some(idx) => idx,
+                None => continue
+            } + url_idx;
+            let url_slice = String::from("https://youtube.com/") + &html[url_idx + 7..url_end];
+            let channel = match video_channels.get(idx * 2 + 1) {
+                Some(channel) => channel.text().await.unwrap_or_default(),
+                None => String::new()
+            };
+
+            options.push(Song {
+                name: title,
+                channel,
+                url: Some(url_slice),
+                file: None,
+                ..Default::default()
+            });
+        }
+
+        self.seen = video_titles.len();
+        Ok(options)
+    }
+}
+
+/// Percent-encode a search query for use in a URL query string. Spaces become `+`.
+fn encode_query(query: &str) -> String {
+    let mut encoded = String::with_capacity(query.len());
+    for byte in query.trim().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{byte:02X}"))
+        }
+    }
+    encoded
 }
@@ -0,0 +1,149 @@
+use std::fs::{create_dir_all, read_to_string};
+use std::process::{Command, Stdio};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::filemanager::{project_dirs, write_atomic};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    pub yt_dlp: YtDlpConfig,
+    pub processing: ProcessingConfig,
+    pub shuffle: ShuffleConfig
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct YtDlpConfig {
+    /// Executable name or path.
+    pub path: String,
+    /// Passed to every yt-dlp invocation, before the URL.
+    pub extra_args: Vec<String>,
+    /// Passed to --audio-quality: 0 (best) to 10 (worst) VBR, or a bitrate such as 128K.
+    pub audio_quality: String,
+    /// Netscape formatted cookies file, for age restricted or members only videos.
+    pub cookies: Option<PathBuf>,
+    /// Passed to --limit-rate, e.g. 1M.
+    pub rate_limit: Option<String>
+}
+
+impl Default for YtDlpConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("yt-dlp"),
+            extra_args: Vec::new(),
+            audio_quality: String::from("5"),
+            cookies: None,
+            rate_limit: None
+        }
+    }
+}
+
+/// Post-processing applied to downloads. Both only store trim points, the file is never modified.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProcessingConfig {
+    /// Skip leading and trailing silence.
+    pub trim_silence: bool,
+    /// Skip segments SponsorBlock marks as non-music, sponsor, self promotion or interaction reminders.
+    pub skip_non_music: bool
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        Self {
+            trim_silence: true,
+            skip_non_music: true
+        }
+    }
+}
+
+/// How "Shuffle Play" treats rated songs.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShuffleConfig {
+    /// Play higher rated songs earlier more often. Unrated songs count as 3 stars.
+    pub weight_by_rating: bool,
+    /// Leave out songs rated 1 star.
+    pub skip_disliked: bool
+}
+
+impl Default for ShuffleConfig {
+    fn default() -> Self {
+        Self {
+            weight_by_rating: false,
+            skip_disliked: true
+        }
+    }
+}
+
+impl YtDlpConfig {
+    /// A yt-dlp command with the configured cookies, rate limit and extra arguments applied.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.path);
+        if let Some(cookies) = &self.cookies {
+            command.arg("--cookies").arg(cookies);
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            command.arg("--limit-rate").arg(rate_limit);
+        }
+        command.args(&self.extra_args);
+        command
+    }
+}
+
+pub fn get_config_file() -> PathBuf {
+    let p = project_dirs();
+    let path = p.config_dir().to_path_buf();
+    let _ = create_dir_all(&path);
+    path.join("config.toml")
+}
+
+pub fn get_config() -> &'static Config {
+    CONFIG.get_or_init(|| load().unwrap_or_default())
+}
+
+/// Load the config file, writing the defaults if there is none, and check that yt-dlp can be run.
+/// Problems are returned as messages for the TUI; the defaults are used if the file is invalid.
+pub fn init() -> Vec<String> {
+    let mut errors: Vec<String> = Vec::new();
+
+    match load() {
+        Ok(config) => { let _ = CONFIG.set(config); }
+        Err(e) => errors.push(e)
+    }
+
+    let yt_dlp = &get_config().yt_dlp;
+    match yt_dlp.command().arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => errors.push(format!("{} --version exited with {status}", yt_dlp.path)),
+        Err(_) => errors.push(format!("yt-dlp not found at '{}', set yt_dlp.path in {}", yt_dlp.path, get_config_file().to_string_lossy()))
+    }
+
+    if let Some(cookies) = &yt_dlp.cookies {
+        if !cookies.exists() {
+            errors.push(format!("Cookies file {} does not exist", cookies.to_string_lossy()));
+        }
+    }
+
+    errors
+}
+
+fn load() -> Result<Config, String> {
+    let file = get_config_file();
+    match read_to_string(&file) {
+        Ok(contents) => toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", file.to_string_lossy(), e.message())),
+        Err(_) => {
+            let config = Config::default();
+            if let Ok(serial) = toml::to_string_pretty(&config) {
+                let _ = write_atomic(&file, serial.as_bytes());
+            }
+            Ok(config)
+        }
+    }
+}
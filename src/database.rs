@@ -0,0 +1,295 @@
+use std::fs::{create_dir_all, read, read_dir, remove_file, rename};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::downloader::Song;
+use crate::filemanager::{now, write_atomic};
+
+// A backup is taken at most this often, and only the newest few are kept.
+const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+const BACKUPS: usize = 5;
+
+/// Each entry upgrades the schema from the version before it. The number applied is stored in
+/// `PRAGMA user_version`, so a database is only ever migrated forwards from where it left off.
+const MIGRATIONS: [&str; 4] = [
+    "CREATE TABLE songs (
+        id INTEGER PRIMARY KEY,
+        key TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        channel TEXT NOT NULL,
+        url TEXT,
+        file TEXT,
+        start_ms INTEGER,
+        end_ms INTEGER,
+        skips TEXT NOT NULL DEFAULT '[]',
+        chapter INTEGER,
+        added_at INTEGER NOT NULL
+    );
+    CREATE TABLE playlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE playlist_entries (
+        playlist_id INTEGER NOT NULL REFERENCES playlists(id) ON DELETE CASCADE,
+        song_id INTEGER NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        added_at INTEGER NOT NULL,
+        PRIMARY KEY (playlist_id, song_id)
+    );
+    CREATE INDEX playlist_entries_position ON playlist_entries (playlist_id, position);
+    CREATE TABLE play_history (
+        song_id INTEGER NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+        played_at INTEGER NOT NULL
+    );
+    CREATE INDEX play_history_song ON play_history (song_id);
+    CREATE TABLE download_state (
+        song_id INTEGER PRIMARY KEY REFERENCES songs(id) ON DELETE CASCADE,
+        state TEXT NOT NULL,
+        error TEXT,
+        updated_at INTEGER NOT NULL
+    );
+    INSERT INTO playlists (name) VALUES ('Library');",
+    "ALTER TABLE songs ADD COLUMN duration_ms INTEGER;",
+    "ALTER TABLE songs ADD COLUMN album TEXT NOT NULL DEFAULT '';",
+    "ALTER TABLE songs ADD COLUMN rating INTEGER;
+    ALTER TABLE songs ADD COLUMN tags TEXT NOT NULL DEFAULT '[]';"
+];
+
+/// Columns read by `song_from_row`, in order.
+pub const SONG_COLUMNS: &str = "songs.name, songs.channel, songs.url, songs.file, songs.start_ms, songs.end_ms, songs.skips, songs.chapter, songs.duration_ms, songs.album, songs.rating, songs.tags";
+
+/// Open the database, creating it if needed and applying any migrations it has not had yet.
+pub fn open(path: &Path) -> Result<Connection, String> {
+    let mut db = Connection::open(path).map_err(|e| format!("Failed to open {}: {e}", path.to_string_lossy()))?;
+    db.pragma_update(None, "foreign_keys", true).map_err(|e| e.to_string())?;
+
+    let version = schema_version(&db).map_err(|e| e.to_string())?;
+    check_version(path, version)?;
+    if version == MIGRATIONS.len() { return Ok(db); }
+
+    let tx = db.transaction().map_err(|e| e.to_string())?;
+    for migration in &MIGRATIONS[version..] {
+        tx.execute_batch(migration).map_err(|e| format!("Failed to migrate {}: {e}", path.to_string_lossy()))?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len() as i64).map_err(|e| e.to_string())?;
+    tx.commit().map_err(|e| e.to_string())?;
+
+    Ok(db)
+}
+
+fn schema_version(db: &Connection) -> rusqlite::Result<usize> {
+    db.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0)).map(|version| version as usize)
+}
+
+fn check_version(path: &Path, version: usize) -> Result<(), String> {
+    match version > MIGRATIONS.len() {
+        true => Err(format!("{} is schema version {version}, newer than this build supports ({})", path.to_string_lossy(), MIGRATIONS.len())),
+        false => Ok(())
+    }
+}
+
+/// The result of `open_or_restore`.
+pub struct Opened {
+    pub db: Connection,
+    /// True if there was no usable database and a new, empty one was created.
+    pub created: bool,
+    /// Set when the database was corrupted and had to be replaced.
+    pub warning: Option<String>
+}
+
+/// Open the database and check its integrity. A corrupted database is moved aside and replaced
+/// with the newest backup that passes the check, or with an empty database if there is none.
+pub fn open_or_restore(path: &Path) -> Result<Opened, String> {
+    let existed = path.exists();
+    // A database written by a newer build is not corrupt and must be left alone.
+    if let Ok(version) = Connection::open(path).and_then(|db| schema_version(&db)) {
+        check_version(path, version)?;
+    }
+
+    let error = match open_checked(path) {
+        Ok(db) => return Ok(Opened { db, created: !existed, warning: None }),
+        Err(e) if !existed => return Err(e),
+        Err(e) => e
+    };
+
+    // The journal belongs to the corrupted file and must not be replayed onto a restored one.
+    let corrupt = path.with_extension(format!("db.corrupt-{}", now()));
+    rename(path, &corrupt).map_err(|e| format!("{error}; failed to move it aside: {e}"))?;
+    for suffix in ["-journal", "-wal", "-shm"] {
+        let side = PathBuf::from(format!("{}{suffix}", path.to_string_lossy()));
+        if side.exists() { let _ = rename(&side, PathBuf::from(format!("{}{suffix}", corrupt.to_string_lossy()))); }
+    }
+
+    for backup in backups(path).iter().rev() {
+        let restored = read(backup).map_err(|e| e.to_string()).and_then(|contents| write_atomic(path, &contents).map_err(|e| e.to_string()));
+        if restored.is_err() { continue; }
+
+        match open_checked(path) {
+            Ok(db) => return Ok(Opened {
+                db,
+                created: false,
+                warning: Some(format!("Library was corrupted ({error}), restored the backup from {}", backup.to_string_lossy()))
+            }),
+            Err(_) => { let _ = remove_file(path); }
+        }
+    }
+
+    let db = open(path)?;
+    Ok(Opened {
+        db,
+        created: true,
+        warning: Some(format!("Library was corrupted ({error}) and there is no usable backup, it was moved to {}", corrupt.to_string_lossy()))
+    })
+}
+
+fn open_checked(path: &Path) -> Result<Connection, String> {
+    let db = open(path)?;
+    let result: String = db.query_row("PRAGMA quick_check", [], |row| row.get(0)).map_err(|e| e.to_string())?;
+    match result.as_str() {
+        "ok" => Ok(db),
+        _ => Err(result)
+    }
+}
+
+fn backup_directory(path: &Path) -> PathBuf {
+    path.with_file_name("backups")
+}
+
+/// Existing backups of the database, oldest first.
+fn backups(path: &Path) -> Vec<PathBuf> {
+    let mut backups = match read_dir(backup_directory(path)) {
+        Ok(contents) => contents.flatten().map(|entry| entry.path())
+            .filter(|path| path.file_name().is_some_and(|name| name.to_string_lossy().starts_with("library-") && name.to_string_lossy().ends_with(".db")))
+            .collect::<Vec<PathBuf>>(),
+        Err(_) => Vec::new()
+    };
+    // Timestamps in the names are zero padded, so they sort by age.
+    backups.sort();
+    backups
+}
+
+/// Copy the database into the backup directory if the newest backup is old enough, then drop the oldest
+/// backups beyond the number kept.
+pub fn backup(db: &Connection, path: &Path) -> Result<(), String> {
+    let directory = backup_directory(path);
+    create_dir_all(&directory).map_err(|e| e.to_string())?;
+
+    let existing = backups(path);
+    let newest = existing.last()
+        .and_then(|backup| backup.file_stem())
+        .and_then(|stem| stem.to_string_lossy().strip_prefix("library-").and_then(|time| time.parse::<i64>().ok()));
+    if newest.is_some_and(|newest| now() - newest < BACKUP_INTERVAL.as_secs() as i64) { return Ok(()); }
+
+    // VACUUM INTO refuses to overwrite, and writes to a temporary name so a crash never leaves a partial backup.
+    let tmp = directory.join("library.db.tmp");
+    let _ = remove_file(&tmp);
+    db.execute("VACUUM INTO ?1", [tmp.to_string_lossy()]).map_err(|e| e.to_string())?;
+    rename(&tmp, directory.join(format!("library-{:020}.db", now()))).map_err(|e| e.to_string())?;
+
+    let backups = backups(path);
+    for old in backups.iter().take(backups.len().saturating_sub(BACKUPS)) {
+        let _ = remove_file(old);
+    }
+    Ok(())
+}
+
+pub fn song_from_row(row: &Row) -> rusqlite::Result<Song> {
+    let skips: String = row.get(6)?;
+    let tags: String = row.get(11)?;
+    let from_millis = |ms: Option<i64>| ms.map(|ms| Duration::from_millis(ms as u64));
+
+    Ok(Song {
+        name: row.get(0)?,
+        channel: row.get(1)?,
+        url: row.get(2)?,
+        file: row.get::<_, Option<String>>(3)?.map(PathBuf::from),
+        start: from_millis(row.get(4)?),
+        end: from_millis(row.get(5)?),
+        skips: serde_json::from_str::<Vec<(u64, u64)>>(&skips).unwrap_or_default().into_iter()
+            .map(|(start, end)| (Duration::from_millis(start), Duration::from_millis(end)))
+            .collect(),
+        chapter: row.get::<_, Option<i64>>(7)?.map(|chapter| chapter as usize),
+        duration: from_millis(row.get(8)?),
+        album: row.get(9)?,
+        rating: row.get(10)?,
+        tags: serde_json::from_str(&tags).unwrap_or_default()
+    })
+}
+
+fn millis(duration: Option<Duration>) -> Option<i64> {
+    duration.map(|duration| duration.as_millis() as i64)
+}
+
+fn tags_json(song: &Song) -> String {
+    serde_json::to_string(&song.tags).unwrap_or_else(|_| String::from("[]"))
+}
+
+fn skips_json(song: &Song) -> String {
+    let skips = song.skips.iter().map(|(start, end)| (start.as_millis() as u64, end.as_millis() as u64)).collect::<Vec<(u64, u64)>>();
+    serde_json::to_string(&skips).unwrap_or_else(|_| String::from("[]"))
+}
+
+pub fn song_id(db: &Connection, song: &Song) -> rusqlite::Result<Option<i64>> {
+    db.query_row("SELECT id FROM songs WHERE key = ?1", [song.key()], |row| row.get(0)).optional()
+}
+
+pub fn song_by_id(db: &Connection, song_id: i64) -> rusqlite::Result<Song> {
+    db.query_row(&format!("SELECT {SONG_COLUMNS} FROM songs WHERE id = ?1"), [song_id], song_from_row)
+}
+
+/// Add a song to the library unless one with the same key is already there, returning its id either way.
+pub fn insert_song(db: &Connection, song: &Song) -> rusqlite::Result<i64> {
+    db.execute(
+        "INSERT OR IGNORE INTO songs (key, name, channel, url, file, start_ms, end_ms, skips, chapter, duration_ms, album, rating, tags, added_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        params![
+            song.key(), song.name, song.channel, song.url,
+            song.file.as_ref().map(|file| file.to_string_lossy().to_string()),
+            millis(song.start), millis(song.end),
+            skips_json(song), song.chapter.map(|chapter| chapter as i64), millis(song.duration), song.album,
+            song.rating, tags_json(song), now()
+        ]
+    )?;
+    db.query_row("SELECT id FROM songs WHERE key = ?1", [song.key()], |row| row.get(0))
+}
+
+/// Overwrite the stored details of the song with the same key.
+pub fn update_song(db: &Connection, song: &Song) -> rusqlite::Result<usize> {
+    db.execute(
+        "UPDATE songs SET name = ?2, channel = ?3, url = ?4, file = ?5, start_ms = ?6, end_ms = ?7, skips = ?8, chapter = ?9, duration_ms = ?10, album = ?11, rating = ?12, tags = ?13 WHERE key = ?1",
+        params![
+            song.key(), song.name, song.channel, song.url,
+            song.file.as_ref().map(|file| file.to_string_lossy().to_string()),
+            millis(song.start), millis(song.end),
+            skips_json(song), song.chapter.map(|chapter| chapter as i64), millis(song.duration), song.album,
+            song.rating, tags_json(song)
+        ]
+    )
+}
+
+/// Store only what a download determines: the file, trim points, skipped segments and length.
+pub fn update_download(db: &Connection, song: &Song) -> rusqlite::Result<usize> {
+    db.execute(
+        "UPDATE songs SET file = ?2, start_ms = ?3, end_ms = ?4, skips = ?5, duration_ms = ?6 WHERE key = ?1",
+        params![
+            song.key(), song.file.as_ref().map(|file| file.to_string_lossy().to_string()),
+            millis(song.start), millis(song.end), skips_json(song), millis(song.duration)
+        ]
+    )
+}
+
+/// Add a song to the end of a playlist, returning 0 if it was already there.
+pub fn append_entry(db: &Connection, playlist_id: i64, song_id: i64) -> rusqlite::Result<usize> {
+    db.execute(
+        "INSERT OR IGNORE INTO playlist_entries (playlist_id, song_id, position, added_at)
+        SELECT ?1, ?2, COALESCE(MAX(position) + 1, 0), ?3 FROM playlist_entries WHERE playlist_id = ?1",
+        params![playlist_id, song_id, now()]
+    )
+}
+
+/// Drop a song from the library once no playlist refers to it any more.
+pub fn delete_if_unreferenced(db: &Connection, song_id: i64) -> rusqlite::Result<usize> {
+    db.execute("DELETE FROM songs WHERE id = ?1 AND NOT EXISTS (SELECT 1 FROM playlist_entries WHERE song_id = ?1)", [song_id])
+}
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{metadata, read_dir, remove_file, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use rodio::Decoder;
+
+use crate::downloader::Song;
+use crate::filemanager::{get_directory, is_managed, Playlist, AUDIO_EXTENSIONS};
+
+// Left in the data directory by downloads that never finished.
+const LEFTOVER_EXTENSIONS: [&str; 4] = ["part", "ytdl", "jpg", "webp"];
+
+/// Kinds of problem the doctor looks for. Each kind is fixed all at once.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    Orphaned,
+    Missing,
+    Broken,
+    Duplicate
+}
+
+impl Problem {
+    pub const ALL: [Problem; 4] = [Problem::Orphaned, Problem::Missing, Problem::Broken, Problem::Duplicate];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Problem::Orphaned => "Files not used by any song",
+            Problem::Missing => "Songs whose file is missing",
+            Problem::Broken => "Songs whose file is empty or cannot be decoded",
+            Problem::Duplicate => "Songs in the library more than once"
+        }
+    }
+
+    pub fn fix(&self) -> &'static str {
+        match self {
+            Problem::Orphaned => "delete the files",
+            Problem::Missing => "mark them as not downloaded, removing local songs",
+            Problem::Broken => "delete the downloaded files and mark them as not downloaded, removing local songs",
+            Problem::Duplicate => "merge each into one song"
+        }
+    }
+}
+
+/// A duplicated song: the copy that is kept, and the copies merged into it.
+pub type Duplicate = (Song, Vec<Song>);
+
+#[derive(Default)]
+pub struct Report {
+    pub orphaned: Vec<PathBuf>,
+    pub missing: Vec<Song>,
+    pub broken: Vec<Song>,
+    pub duplicates: Vec<Duplicate>
+}
+
+impl Report {
+    pub fn count(&self, problem: Problem) -> usize {
+        match problem {
+            Problem::Orphaned => self.orphaned.len(),
+            Problem::Missing => self.missing.len(),
+            Problem::Broken => self.broken.len(),
+            Problem::Duplicate => self.duplicates.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        Problem::ALL.iter().all(|problem| self.count(*problem) == 0)
+    }
+
+    /// One line per affected file or song.
+    pub fn describe(&self, problem: Problem) -> Vec<String> {
+        let song = |song: &Song| match song.file {
+            Some(ref file) => format!("{} ({})", song.name, file.to_string_lossy()),
+            None => song.name.clone()
+        };
+
+        match problem {
+            Problem::Orphaned => self.orphaned.iter().map(|path| path.to_string_lossy().to_string()).collect(),
+            Problem::Missing => self.missing.iter().map(song).collect(),
+            Problem::Broken => self.broken.iter().map(song).collect(),
+            Problem::Duplicate => self.duplicates.iter().map(|(keep, duplicates)| format!("{} ({} copies)", keep.name, duplicates.len() + 1)).collect()
+        }
+    }
+}
+
+fn is_decodable(path: &Path) -> bool {
+    match File::open(path) {
+        Ok(file) => Decoder::new(BufReader::new(file)).is_ok(),
+        Err(_) => false
+    }
+}
+
+/// What makes two songs the same: the video they come from, or the file for local songs, and the chapter.
+fn identity(song: &Song) -> String {
+    let source = match song.file {
+        // A download imported as a local file is named by its video id.
+        Some(ref file) if song.url.is_none() && is_managed(file) => file.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+        _ => song.file_id()
+    };
+
+    match song.chapter {
+        Some(chapter) => format!("{source}#{chapter}"),
+        None => source
+    }
+}
+
+/// Look through the whole library and the data directory for problems. Nothing is changed.
+pub fn check(library: &Playlist) -> Report {
+    let songs = library.library_songs();
+    let mut report = Report::default();
+
+    let used = songs.iter().filter_map(|song| song.file.clone()).collect::<HashSet<PathBuf>>();
+    if let Ok(contents) = read_dir(get_directory()) {
+        let mut orphaned = contents.flatten().map(|entry| entry.path())
+            .filter(|path| path.is_file() && !used.contains(path))
+            .filter(|path| {
+                let name = path.file_name().unwrap_or_default().to_string_lossy().to_lowercase();
+                let extension = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+                AUDIO_EXTENSIONS.contains(&extension.as_str()) || LEFTOVER_EXTENSIONS.contains(&extension.as_str()) || name.ends_with(".info.json")
+            })
+            .collect::<Vec<PathBuf>>();
+        orphaned.sort();
+        report.orphaned = orphaned;
+    }
+
+    // Songs sharing a file, e.g. chapters of one video, only need the file checked once.
+    let mut checked: HashMap<PathBuf, bool> = HashMap::new();
+    for song in &songs {
+        let file = match song.file {
+            Some(ref file) => file,
+            None => continue
+        };
+
+        if !file.exists() {
+            report.missing.push(song.clone());
+            continue;
+        }
+
+        let broken = *checked.entry(file.clone()).or_insert_with(|| metadata(file).is_ok_and(|metadata| metadata.len() == 0) || !is_decodable(file));
+        if broken { report.broken.push(song.clone()); }
+    }
+
+    let mut groups: HashMap<String, Vec<Song>> = HashMap::new();
+    let mut order: Vec<String> = Vec::new();
+    for song in songs {
+        let identity = identity(&song);
+        if !groups.contains_key(&identity) { order.push(identity.clone()); }
+        groups.entry(identity).or_default().push(song);
+    }
+    for identity in order {
+        let mut group = groups.remove(&identity).unwrap_or_default();
+        if group.len() < 2 { continue; }
+        // Keep a downloaded copy if there is one, otherwise the oldest.
+        let keep = group.iter().position(|song| song.file.is_some()).unwrap_or_default();
+        let keep = group.remove(keep);
+        report.duplicates.push((keep, group));
+    }
+
+    report
+}
+
+/// Clear the stored file of songs that have lost it, so they can be downloaded again.
+/// Local songs have nothing to download from, so they are removed instead.
+fn forget_files(library: &mut Playlist, songs: &[Song]) -> Result<usize, String> {
+    for song in songs {
+        match song.url {
+            Some(_) => library.update_song(&Song { file: None, ..song.clone() }),
+            None => library.remove_from_library(song)?
+        }
+    }
+    Ok(songs.len())
+}
+
+/// Fix every instance of one kind of problem from a report, returning how many were fixed.
+pub fn fix(library: &mut Playlist, report: &Report, problem: Problem) -> Result<usize, String> {
+    match problem {
+        Problem::Orphaned => Ok(report.orphaned.iter().filter(|path| remove_file(path).is_ok()).count()),
+        Problem::Missing => forget_files(library, &report.missing),
+        Problem::Broken => {
+            // Files the user imported from elsewhere are left alone, only downloads are deleted.
+            for file in report.broken.iter().filter_map(|song| song.file.as_ref()).collect::<HashSet<&PathBuf>>() {
+                if is_managed(file) { let _ = remove_file(file); }
+            }
+            forget_files(library, &report.broken)
+        }
+        Problem::Duplicate => {
+            for (keep, duplicates) in &report.duplicates {
+                library.merge_songs(keep, duplicates)?;
+            }
+            Ok(report.duplicates.len())
+        }
+    }
+}
+
+/// `rmusic doctor [--fix]`: print the problems found, fixing them all if asked to.
+pub fn run_cli(fix_problems: bool) -> Result<(), String> {
+    let mut library = Playlist::load_playlist()?;
+    if let Some(warning) = library.take_warning() { println!("{warning}"); }
+
+    let report = check(&library);
+    if report.is_empty() {
+        println!("No problems found.");
+        return Ok(());
+    }
+
+    for problem in Problem::ALL {
+        if report.count(problem) == 0 { continue; }
+        println!("{} ({}), fix: {}", problem.label(), report.count(problem), problem.fix());
+        for line in report.describe(problem) {
+            println!("  {line}");
+        }
+
+        if fix_problems {
+            let fixed = fix(&mut library, &report, problem)?;
+            println!("  Fixed {fixed} of {}", report.count(problem));
+        }
+    }
+
+    if !fix_problems { println!("Run rmusic doctor --fix to fix every problem."); }
+    Ok(())
+}
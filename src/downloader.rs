@@ -1,64 +1,528 @@
-use std::fs::read_to_string;
-use std::process::Command;
-use std::path::PathBuf;
-use std::io::Write;
-use std::fs::File;
+use std::process::{Child, Stdio};
+use std::time::{Duration, Instant};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::fs::{read_dir, remove_file};
+use std::thread::spawn;
 
-use crate::filemanager::get_directory;
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_config;
+use crate::processing::{process, Chapter, NON_MUSIC_CATEGORIES};
+use crate::tags::write_tags;
 
 const SEPARATOR: char = '˾';
 
-#[derive(PartialEq, Eq, Hash, Clone)]
+#[derive(PartialEq, Eq, Hash, Clone, Default, Serialize, Deserialize)]
 pub struct Song {
     pub name: String,
     pub channel: String,
-    pub url: String,
-    pub file: Option<PathBuf>
+    /// None for songs imported from local files.
+    pub url: Option<String>,
+    pub file: Option<PathBuf>,
+    /// Where playback starts and stops within the file, e.g. to trim silence.
+    #[serde(default)]
+    pub start: Option<Duration>,
+    #[serde(default)]
+    pub end: Option<Duration>,
+    /// Segments within the file that are skipped during playback.
+    #[serde(default)]
+    pub skips: Vec<(Duration, Duration)>,
+    /// Index of the chapter this song was split from, when several songs share one video.
+    #[serde(default)]
+    pub chapter: Option<usize>,
+    /// Length of the whole file or video, where known.
+    #[serde(default)]
+    pub duration: Option<Duration>,
+    #[serde(default)]
+    pub album: String,
+    /// 1 to 5 stars, 1 meaning disliked. None if not rated.
+    #[serde(default)]
+    pub rating: Option<u8>,
+    #[serde(default)]
+    pub tags: Vec<String>
 }
 
-impl Song {
-    pub fn serialise(&self) -> String {
-        format!("{}{}{}{}{}{}{}", self.name, SEPARATOR, self.channel, SEPARATOR, self.url, SEPARATOR, match self.file {
-            Some(ref f) => f.to_string_lossy().to_string(),
-            None => String::from("_")
-        })
-    }
+fn deserialise_offset(component: Option<&String>) -> Option<Duration> {
+    component.and_then(|c| c.parse::<u64>().ok()).map(Duration::from_millis)
+}
+
+/// 64 bit FNV-1a, used because the standard library's hasher is not stable between releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
 
-    pub fn deserialise(serial: String) -> Self {
+impl Song {
+    /// Parse a line of the legacy ˾ separated playlist.txt, returning None if it is truncated.
+    pub fn deserialise_legacy(serial: &str) -> Option<Self> {
         let components = serial.split(SEPARATOR).map(|component| component.to_string()).collect::<Vec<String>>();
-        Self {
+        if components.len() < 3 { return None; }
+
+        Some(Self {
             name: components[0].clone(),
             channel: components[1].clone(),
-            url: components[2].clone(),
+            url: if components[2] == "_" { None } else { Some(components[2].clone()) },
             file: match components.get(3) {
-                Some(file) => if *file == String::from("_") { None } else { Some(PathBuf::from(file)) },
+                Some(file) => if *file == "_" { None } else { Some(PathBuf::from(file)) },
                 None => None
-            }
+            },
+            start: deserialise_offset(components.get(4)),
+            end: deserialise_offset(components.get(5)),
+            skips: match components.get(6) {
+                Some(skips) => skips.split(',').filter_map(|skip| {
+                    let (start, end) = skip.split_once('-')?;
+                    Some((Duration::from_millis(start.parse().ok()?), Duration::from_millis(end.parse().ok()?)))
+                }).collect(),
+                None => Vec::new()
+            },
+            chapter: components.get(7).and_then(|c| c.parse::<usize>().ok()),
+            duration: None,
+            album: String::new(),
+            rating: None,
+            tags: Vec::new()
+        })
+    }
+
+    /// A stable name for the downloaded file: the YouTube video id, or a hash of the URL for other sites.
+    pub fn file_id(&self) -> String {
+        let url = match self.url {
+            Some(ref url) => url,
+            None => return format!("{:016x}", fnv1a(self.key().as_bytes()))
+        };
+
+        let video_id = url.split(['?', '&']).find_map(|param| param.strip_prefix("v="))
+            .or(url.split("youtu.be/").nth(1))
+            .or(url.split("/shorts/").nth(1))
+            .map(|id| id.split(['?', '&', '#', '/']).next().unwrap_or(""));
+
+        match video_id {
+            Some(id) if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => id.to_string(),
+            _ => format!("{:016x}", fnv1a(url.as_bytes()))
+        }
+    }
+
+    /// Identifies the song within a playlist: its URL, plus the chapter if it was split from a longer video.
+    /// Local songs without a URL are identified by their file instead.
+    pub fn key(&self) -> String {
+        let source = match (&self.url, &self.file) {
+            (Some(url), _) => url.clone(),
+            (None, Some(file)) => format!("file://{}", file.to_string_lossy()),
+            (None, None) => self.name.clone()
+        };
+
+        match self.chapter {
+            Some(chapter) => format!("{source}#{chapter}"),
+            None => source
         }
     }
 
-    pub fn download(&mut self, file_id: usize) {
-        let old: String = self.serialise();
+    /// True if the song is rated 1 star.
+    pub fn disliked(&self) -> bool {
+        self.rating == Some(1)
+    }
+
+    /// The parts of the file to play, in order, after applying the trim points and skipped segments.
+    /// An end of None means play to the end of the file.
+    pub fn play_ranges(&self) -> Vec<(Duration, Option<Duration>)> {
+        let mut ranges: Vec<(Duration, Option<Duration>)> = Vec::new();
+        let mut position = self.start.unwrap_or_default();
 
-        self.file = Some(get_directory().join(PathBuf::from(format!("{file_id}.mp3"))));
+        let mut skips = self.skips.clone();
+        skips.sort();
+        for (skip_start, skip_end) in skips {
+            if self.end.is_some_and(|end| skip_start >= end) { break; }
+            if skip_start > position { ranges.push((position, Some(skip_start))); }
+            position = position.max(skip_end);
+        }
+
+        if self.end.is_none_or(|end| end > position) {
+            ranges.push((position, self.end));
+        }
+        ranges
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum DownloadState {
+    Queued,
+    Downloading(f32),
+    Retrying(u32),
+    Done,
+    Failed(String),
+    Cancelled
+}
 
-        let mut contents = match read_to_string(get_directory().join(PathBuf::from("playlist.txt"))) {
-            Ok(contents) => contents.lines().map(|x| x.to_string()).collect::<Vec<String>>(),
-            Err(_) => return
+impl DownloadState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, DownloadState::Done | DownloadState::Failed(_) | DownloadState::Cancelled)
+    }
+}
+
+// The song with its file and trim points filled in, plus its chapters, once the download succeeds.
+type Downloaded = Arc<Mutex<Option<(Song, Vec<Chapter>)>>>;
+
+const MAX_RETRIES: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+// yt-dlp errors that trying again will not fix.
+const PERMANENT_ERRORS: [&str; 6] = [
+    "Video unavailable", "Private video", "This video is private", "This video has been removed",
+    "This video is no longer available", "Sign in to confirm your age"
+];
+
+struct DownloadJob {
+    song: Song,
+    finished: Downloaded,
+    output: PathBuf,
+    state: Arc<Mutex<DownloadState>>,
+    child: Arc<Mutex<Option<Child>>>,
+    started: bool,
+    reported: bool,
+    attempts: u32,
+    retry_at: Option<Instant>,
+    /// Set when the failure cannot be fixed by retrying, e.g. the video is private or yt-dlp is missing.
+    permanent: Arc<Mutex<bool>>
+}
+
+impl DownloadJob {
+    fn new(song: Song, output: PathBuf) -> Self {
+        Self {
+            song,
+            finished: Arc::new(Mutex::new(None)),
+            output,
+            state: Arc::new(Mutex::new(DownloadState::Queued)),
+            child: Arc::new(Mutex::new(None)),
+            started: false,
+            reported: false,
+            attempts: 0,
+            retry_at: None,
+            permanent: Arc::new(Mutex::new(false))
+        }
+    }
+}
+
+/// Runs yt-dlp downloads in the background, at most `limit` at a time, tracking the progress of each.
+/// Failed downloads are retried with exponential backoff before being reported as failed.
+pub struct DownloadManager {
+    jobs: Vec<DownloadJob>,
+    limit: usize
+}
+
+impl DownloadManager {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            jobs: Vec::new(),
+            limit
+        }
+    }
+
+    pub fn queue(&mut self, song: Song, output: PathBuf) {
+        if self.jobs.iter().any(|job| job.song.key() == song.key() && !job.state.lock().unwrap().is_finished()) {
+            return;
+        }
+
+        self.jobs.retain(|job| job.song.key() != song.key());
+        self.jobs.push(DownloadJob::new(song, output));
+    }
+
+    pub fn state(&self, key: &str) -> Option<DownloadState> {
+        self.jobs.iter().find(|job| job.song.key() == key).map(|job| job.state.lock().unwrap().clone())
+    }
+
+    /// How many downloads are queued or running.
+    pub fn active(&self) -> usize {
+        self.jobs.iter().filter(|job| !job.state.lock().unwrap().is_finished()).count()
+    }
+
+    /// Stop a queued or running download, killing yt-dlp and removing its partial output.
+    pub fn cancel(&mut self, key: &str) -> bool {
+        let job = match self.jobs.iter().find(|job| job.song.key() == key) {
+            Some(job) => job,
+            None => return false
         };
-        let new: String = self.serialise();
-        let idx = contents.iter().position(|n| *n == old).unwrap();
-        contents[idx] = new;
-        let mut file = File::create(get_directory().join(PathBuf::from("playlist.txt"))).unwrap();
-        for line in contents {
-            let _ = writeln!(file, "{line}");
+
+        {
+            let mut state = job.state.lock().unwrap();
+            if state.is_finished() { return false; }
+            *state = DownloadState::Cancelled;
         }
 
-        // yt-dlp -f "bestaudio" --extract-audio --audio-format mp3 -o <id>.mp3 <url>
+        if let Some(child) = job.child.lock().unwrap().as_mut() {
+            let _ = child.kill();
+        }
+        true
+    }
+
+    /// Queue every download that failed after exhausting its retries again, returning the requeued songs.
+    pub fn retry_failed(&mut self) -> Vec<Song> {
+        let mut retried: Vec<Song> = Vec::new();
+        for job in &mut self.jobs {
+            if !matches!(*job.state.lock().unwrap(), DownloadState::Failed(_)) { continue; }
+            *job = DownloadJob::new(job.song.clone(), job.output.clone());
+            retried.push(job.song.clone());
+        }
+        retried
+    }
+
+    /// Start queued jobs while there is capacity and return the jobs that finished since the last poll.
+    /// Successfully downloaded songs are returned with their file and trim points filled in,
+    /// along with the chapters found in the video.
+    pub fn poll(&mut self) -> Vec<(Song, Vec<Chapter>, DownloadState)> {
+        let mut finished: Vec<(Song, Vec<Chapter>, DownloadState)> = Vec::new();
+        let mut running: usize = 0;
+
+        for job in &mut self.jobs {
+            let mut state = job.state.lock().unwrap();
+            if let DownloadState::Failed(_) = *state {
+                if !job.reported && job.attempts < MAX_RETRIES && !*job.permanent.lock().unwrap() {
+                    job.attempts += 1;
+                    job.started = false;
+                    job.retry_at = Some(Instant::now() + RETRY_BACKOFF * 2u32.pow(job.attempts - 1));
+                    *state = DownloadState::Retrying(job.attempts);
+                    continue;
+                }
+            }
+
+            if state.is_finished() {
+                if !job.reported {
+                    job.reported = true;
+                    let (song, chapters) = job.finished.lock().unwrap().take().unwrap_or((job.song.clone(), Vec::new()));
+                    finished.push((song, chapters, state.clone()));
+                }
+            } else if job.started {
+                running += 1;
+            }
+        }
+
+        for job in &mut self.jobs {
+            if running >= self.limit { break; }
+            if job.started || job.state.lock().unwrap().is_finished() { continue; }
+            if job.retry_at.is_some_and(|retry_at| retry_at > Instant::now()) { continue; }
+
+            job.started = true;
+            running += 1;
+            let state = Arc::clone(&job.state);
+            let child = Arc::clone(&job.child);
+            let song = job.song.clone();
+            let result = Arc::clone(&job.finished);
+            let output = job.output.clone();
+            let permanent = Arc::clone(&job.permanent);
+            spawn(move || run_download(song, output, state, child, result, permanent));
+        }
+
+        finished
+    }
+}
+
+/// Update a job's state unless it has been cancelled in the meantime.
+fn set_state(state: &Mutex<DownloadState>, new: DownloadState) {
+    let mut state = state.lock().unwrap();
+    if *state != DownloadState::Cancelled { *state = new; }
+}
+
+fn run_download(song: Song, output: PathBuf, state: Arc<Mutex<DownloadState>>, slot: Arc<Mutex<Option<Child>>>, finished: Downloaded, permanent: Arc<Mutex<bool>>) {
+    // yt-dlp --newline -f "bestaudio" --extract-audio --audio-format mp3 --audio-quality <quality> --write-thumbnail --convert-thumbnails jpg
+    //     --write-info-json [--sponsorblock-mark <categories>] -o <id>.%(ext)s <url>
+
+    let yt_dlp = &get_config().yt_dlp;
+    let mut command = yt_dlp.command();
+    command.arg("--newline").arg("-f").arg("bestaudio").arg("--extract-audio").arg("--audio-format").arg("mp3")
+        .arg("--audio-quality").arg(&yt_dlp.audio_quality).arg("--write-thumbnail").arg("--convert-thumbnails").arg("jpg")
+        .arg("--write-info-json");
+    if get_config().processing.skip_non_music {
+        command.arg("--sponsorblock-mark").arg(NON_MUSIC_CATEGORIES);
+    }
+    let url = match song.url {
+        Some(ref url) => url,
+        None => {
+            *permanent.lock().unwrap() = true;
+            set_state(&state, DownloadState::Failed(String::from("Song has no URL to download from")));
+            return;
+        }
+    };
+    // Files are named by video, so the mp3 may already exist for another song, e.g. another chapter.
+    let existed = output.metadata().is_ok_and(|metadata| metadata.len() > 0);
+    let child = command.arg("-o").arg(output.with_extension("%(ext)s")).arg(url).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            *permanent.lock().unwrap() = true;
+            set_state(&state, DownloadState::Failed(format!("Failed to spawn yt-dlp: {e:?}")));
+            return;
+        }
+    };
+
+    set_state(&state, DownloadState::Downloading(0.0));
+
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    {
+        // Hand the child over so it can be killed, unless the job was cancelled while spawning.
+        let mut slot = slot.lock().unwrap();
+        if *state.lock().unwrap() == DownloadState::Cancelled { let _ = child.kill(); }
+        *slot = Some(child);
+    }
+
+    let error: Arc<Mutex<String>> = Arc::new(Mutex::new(String::new()));
+    let stderr_handle = stderr.map(|stderr| {
+        let error = Arc::clone(&error);
+        spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                if line.starts_with("ERROR") { *error.lock().unwrap() = line; }
+            }
+        })
+    });
+
+    if let Some(stdout) = stdout {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if let Some(percent) = parse_progress(&line) {
+                set_state(&state, DownloadState::Downloading(percent));
+            }
+        }
+    }
+
+    if let Some(handle) = stderr_handle { let _ = handle.join(); }
+
+    let status = match slot.lock().unwrap().take() {
+        Some(mut child) => child.wait(),
+        None => return
+    };
+
+    let result = match status {
+        Ok(status) if status.success() => match output.metadata() {
+            Ok(metadata) if metadata.len() > 0 => DownloadState::Done,
+            _ => DownloadState::Failed(String::from("yt-dlp finished without producing an audio file"))
+        },
+        Ok(status) => {
+            let error = error.lock().unwrap().clone();
+            if PERMANENT_ERRORS.iter().any(|pattern| error.contains(pattern)) { *permanent.lock().unwrap() = true; }
+            DownloadState::Failed(if error.is_empty() { format!("yt-dlp exited with {status}") } else { error })
+        }
+        Err(e) => DownloadState::Failed(format!("{e:?}"))
+    };
+
+    let cancelled = *state.lock().unwrap() == DownloadState::Cancelled;
+    if cancelled || result != DownloadState::Done {
+        remove_partial_files(&output, existed);
+    } else {
+        // Tagging is best effort: an untagged file still plays fine in rmusic.
+        // A chapter shares the file with its siblings, so its own title is not written into it.
+        let cover = output.with_extension("jpg");
+        if song.chapter.is_none() { let _ = write_tags(&output, &song, Some(&cover)); }
+        let _ = remove_file(&cover);
+
+        let mut downloaded = song.clone();
+        downloaded.file = Some(output.clone());
+        let chapters = process(&mut downloaded, &output, &get_config().processing);
+        *finished.lock().unwrap() = Some((downloaded, chapters));
+    }
+    set_state(&state, result);
+}
+
+/// Delete everything yt-dlp may have left behind for an output path: `.part` fragments,
+/// the unconverted download and any half-written mp3. An mp3 that was there before the download is kept.
+fn remove_partial_files(output: &Path, keep_output: bool) {
+    let (directory, stem) = match (output.parent(), output.file_stem()) {
+        (Some(directory), Some(stem)) => (directory, format!("{}.", stem.to_string_lossy())),
+        _ => return
+    };
+
+    let contents = match read_dir(directory) {
+        Ok(contents) => contents,
+        Err(_) => return
+    };
+
+    for entry in contents.flatten() {
+        if keep_output && entry.path() == output { continue; }
+        if entry.file_name().to_string_lossy().starts_with(&stem) {
+            let _ = remove_file(entry.path());
+        }
+    }
+}
+
+/// Extract the percentage from a yt-dlp progress line such as `[download]  42.3% of 3.45MiB at 1.2MiB/s`.
+fn parse_progress(line: &str) -> Option<f32> {
+    let rest = line.strip_prefix("[download]")?.trim_start();
+    let percent = rest.split('%').next()?;
+    percent.trim().parse::<f32>().ok()
+}
+
+/// Returns true if the input is a link to YouTube rather than a search query.
+pub fn is_youtube_url(input: &str) -> bool {
+    let input = input.trim();
+    let stripped = input.strip_prefix("https://").or(input.strip_prefix("http://")).unwrap_or(input);
+    let host = stripped.split('/').next().unwrap_or("");
+    let host = host.strip_prefix("www.").or(host.strip_prefix("m.")).or(host.strip_prefix("music.")).unwrap_or(host);
+    host == "youtube.com" || host == "youtu.be"
+}
+
+/// Resolve a video or playlist URL to its songs without downloading anything.
+pub async fn resolve_url(url: &str) -> Result<Vec<Song>, String> {
+    // yt-dlp --flat-playlist --print "%(title)s\t%(channel,uploader)s\t%(id)s" <url>
+
+    let url = channel_videos_url(url.trim());
+    let mut command = tokio::process::Command::from(get_config().yt_dlp.command());
+    let output = match command.arg("--flat-playlist").arg("--print")
+        .arg("%(title)s\t%(channel,uploader)s\t%(id)s\t%(duration)s").arg(&url).output().await {
+        Ok(output) => output,
+        Err(e) => return Err(format!("Failed to run yt-dlp: {e:?}"))
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("yt-dlp could not resolve {}: {}", url, stderr.lines().last().unwrap_or("unknown error")));
+    }
+
+    let songs = String::from_utf8_lossy(&output.stdout).lines().filter_map(|line| {
+        let mut fields = line.splitn(4, '\t');
+        let name = fields.next()?.to_string();
+        let channel = fields.next()?;
+        let id = fields.next()?;
+        let duration = fields.next().and_then(|duration| duration.parse::<f64>().ok());
+        Some(Song {
+            name,
+            channel: if channel == "NA" { String::new() } else { channel.to_string() },
+            url: Some(format!("https://youtube.com/watch?v={id}")),
+            file: None,
+            duration: duration.map(Duration::from_secs_f64),
+            ..Default::default()
+        })
+    }).collect::<Vec<Song>>();
+
+    if songs.is_empty() {
+        return Err(format!("No videos found at {url}"));
+    }
+
+    Ok(songs)
+}
+
+/// A bare channel URL resolves to its tabs rather than its uploads, so point it at the videos tab.
+fn channel_videos_url(url: &str) -> String {
+    let (base, query) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+    let path = base.split("://").last().unwrap_or(base);
+    let segments = path.split('/').skip(1).filter(|segment| !segment.is_empty()).collect::<Vec<&str>>();
+    let is_channel = match segments.first() {
+        Some(first) => (first.starts_with('@') && segments.len() == 1) || (matches!(*first, "channel" | "c" | "user") && segments.len() == 2),
+        None => false
+    };
+
+    if is_channel {
+        format!("{}/videos{query}", base.trim_end_matches('/'))
+    } else {
+        url.to_string()
+    }
+}
 
-        let _ = Command::new("yt-dlp").arg("-f").arg("bestaudio").arg("--extract-audio").arg("--audio-format").arg("mp3").arg("-o").arg(format!(
-            "{}/{}.mp3", get_directory().to_string_lossy().to_string(), file_id
-        )).arg(&self.url).stdout(std::process::Stdio::null()).stderr(std::process::Stdio::null()).spawn();
+#[cfg(test)]
+mod tests {
+    use super::channel_videos_url;
 
+    #[test]
+    fn channel_videos_url_keeps_query() {
+        assert_eq!(channel_videos_url("https://youtube.com/@name?si=abc"), "https://youtube.com/@name/videos?si=abc");
+        assert_eq!(channel_videos_url("https://www.youtube.com/channel/UC123/#top"), "https://www.youtube.com/channel/UC123/videos#top");
+        assert_eq!(channel_videos_url("https://youtube.com/@name/"), "https://youtube.com/@name/videos");
+        assert_eq!(channel_videos_url("https://youtube.com/watch?v=abc"), "https://youtube.com/watch?v=abc");
     }
 }
@@ -1,128 +1,854 @@
 use std::collections::HashSet;
 use std::fs::read_to_string;
 use std::fs::create_dir_all;
-use std::fs::OpenOptions;
-use std::path::PathBuf;
-use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::fs::{read_dir, remove_file, rename, File};
 use std::io::Write;
-use std::fs::File;
 
 use directories::ProjectDirs;
+use serde::Deserialize;
+use serde_json::Value;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, Params};
 
-use crate::downloader::Song;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::database::{self, append_entry, delete_if_unreferenced, Opened, insert_song, song_by_id, song_from_row, song_id, SONG_COLUMNS};
+use crate::downloader::{DownloadState, Song};
+use crate::processing::Chapter;
+use crate::tags::{read_duration, read_tags, write_tags};
+
+
+pub fn project_dirs() -> ProjectDirs {
+    ProjectDirs::from("com", "timeparadox", "rmusic").unwrap()
+}
+
+/// Seconds since the unix epoch, as stored in the library and caches.
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
 
 pub fn get_directory() -> PathBuf {
-    let p = ProjectDirs::from("com", "timeparadox", "rmusic").unwrap();
+    let p = project_dirs();
     let path = p.data_dir().to_path_buf();
     let _ = create_dir_all(&path);
     path
 }
 
-pub fn find_smallest_unused_id(playlist: &Vec<Song>) -> Result<usize, ()> {
-    let mut smallest_id: usize = 0;
+/// True if the file is in the rmusic data directory, i.e. it was downloaded rather than imported from elsewhere.
+pub fn is_managed(file: &Path) -> bool {
+    file.parent() == Some(get_directory().as_path())
+}
+
+/// Write a file so that a crash or full disk leaves either the old or the new contents, never a truncated mix.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_file_name(format!("{}.tmp", path.file_name().unwrap_or_default().to_string_lossy()));
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    rename(&tmp, path)?;
+
+    // Make the rename itself durable.
+    if let Some(parent) = path.parent() {
+        if let Ok(directory) = File::open(parent) { let _ = directory.sync_all(); }
+    }
+    Ok(())
+}
+
+/// Move downloads still named by the old numeric id scheme (`N.mp3`) to their stable id,
+/// returning true if any song was changed.
+fn migrate_numeric_files(songs: &mut [Song]) -> bool {
+    let mut changed = false;
+
+    for idx in 0..songs.len() {
+        let old = match songs[idx].file {
+            Some(ref file) if songs[idx].url.is_some() && is_managed(file) => file.clone(),
+            _ => continue
+        };
+        if old.file_stem().is_none_or(|stem| stem.to_string_lossy().parse::<usize>().is_err()) { continue; }
+
+        let new = get_directory().join(format!("{}.mp3", songs[idx].file_id()));
+        if new == old { continue; }
+        // Older versions recorded the file before yt-dlp ran, so it may never have been downloaded at all.
+        // Such songs are marked as not downloaded so they are offered for download again.
+        let file = if new.exists() {
+            Some(new)
+        } else if !old.exists() {
+            None
+        } else if rename(&old, &new).is_ok() {
+            Some(new)
+        } else {
+            continue;
+        };
+
+        // Chapters split from the same video share the file.
+        for song in songs.iter_mut() {
+            if song.file.as_ref() == Some(&old) { song.file = file.clone(); }
+        }
+        changed = true;
+    }
+
+    changed
+}
+
+/// Recover the library from the tags of downloaded files when there is no library file.
+fn rebuild_from_tags() -> Vec<Song> {
     let contents = match read_dir(get_directory()) {
         Ok(contents) => contents,
-        Err(_) => return Err(())
+        Err(_) => return vec![]
     };
 
-    let mut used_ids: HashSet<usize> = HashSet::new();
+    let mut songs = contents.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "mp3"))
+        .filter_map(|path| read_tags(&path))
+        .collect::<Vec<Song>>();
+    songs.sort_by(|a, b| a.file.cmp(&b.file));
+    songs
+}
+
+/// Audio formats the player can decode.
+pub const AUDIO_EXTENSIONS: [&str; 4] = ["mp3", "flac", "ogg", "wav"];
+
+/// Find every audio file under a directory, recursively, as songs built from their tags.
+pub fn scan_directory(directory: &Path) -> Vec<Song> {
+    let mut songs: Vec<Song> = Vec::new();
+    let mut directories: Vec<PathBuf> = vec![directory.to_path_buf()];
 
-    for entry in contents {
-        let item = match entry {
-            Ok(entry) => entry,
+    while let Some(directory) = directories.pop() {
+        let contents = match read_dir(&directory) {
+            Ok(contents) => contents,
             Err(_) => continue
         };
 
-        let filename = item.file_name().to_string_lossy().to_string();
-        if let Some(extension) = item.path().extension() {
-            let extension = extension.to_string_lossy().to_string();
-            if extension == "mp3" {
-                let id = match filename.strip_suffix(".mp3").unwrap().parse::<usize>() {
-                    Ok(id) => id,
-                    Err(_) => continue
-                };
-
-                used_ids.insert(id);
+        for path in contents.flatten().map(|entry| entry.path()) {
+            if path.is_dir() {
+                directories.push(path);
+            } else if path.extension().is_some_and(|extension| AUDIO_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())) {
+                let path = path.canonicalize().unwrap_or(path);
+                if let Some(song) = read_tags(&path) { songs.push(song); }
             }
         }
+    }
 
-        for song in playlist {
-            if let Some(f) = &song.file {
-                let name = f.file_name().unwrap().to_string_lossy().to_string();
-                let id = name.strip_suffix(".mp3").unwrap().parse::<usize>().unwrap();
-                used_ids.insert(id);
-            }
+    songs.sort_by(|a, b| a.file.cmp(&b.file));
+    songs
+}
+
+/// The last version of library.json, which has since been replaced by the database.
+const LIBRARY_VERSION: u64 = 1;
+
+#[derive(Deserialize)]
+struct LibraryFile {
+    songs: Vec<Song>
+}
+
+/// Read the songs out of a library.json, checking it was written by a version this build knows.
+fn migrate_library(library: Value) -> Result<Vec<Song>, String> {
+    let version = library.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > LIBRARY_VERSION {
+        return Err(format!("library.json is version {version}, newer than this build supports ({LIBRARY_VERSION})"));
+    }
+
+    serde_json::from_value::<LibraryFile>(library)
+        .map(|library| library.songs)
+        .map_err(|e| format!("Invalid library.json: {e}"))
+}
+
+/// Read the ˾ separated playlist.txt used before library.json, skipping lines that cannot be parsed.
+fn read_legacy_playlist(file: &Path) -> Option<Vec<Song>> {
+    let contents = read_to_string(file).ok()?;
+    Some(contents.lines().filter_map(Song::deserialise_legacy).collect())
+}
+
+/// The library from before the database, if there is one: library.json, or the older playlist.txt.
+fn read_previous_library() -> Result<Option<Vec<Song>>, String> {
+    if let Ok(contents) = read_to_string(get_directory().join("library.json")) {
+        let library = serde_json::from_str::<Value>(&contents).map_err(|e| format!("Invalid library.json: {e}"))?;
+        return migrate_library(library).map(Some);
+    }
+
+    Ok(read_legacy_playlist(&get_directory().join("playlist.txt")))
+}
+
+fn download_state_label(state: &DownloadState) -> (&'static str, Option<String>) {
+    match state {
+        DownloadState::Queued | DownloadState::Downloading(_) | DownloadState::Retrying(_) => ("queued", None),
+        DownloadState::Done => ("done", None),
+        DownloadState::Failed(e) => ("failed", Some(e.clone())),
+        DownloadState::Cancelled => ("cancelled", None)
+    }
+}
+
+/// A playlist as listed in the playlist picker.
+pub struct PlaylistInfo {
+    pub id: i64,
+    pub name: String,
+    pub songs: usize
+}
+
+/// Orders a playlist can be sorted into.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Title,
+    Channel,
+    Added,
+    PlayCount,
+    Duration
+}
+
+impl SortOrder {
+    pub const ALL: [SortOrder; 5] = [SortOrder::Title, SortOrder::Channel, SortOrder::Added, SortOrder::PlayCount, SortOrder::Duration];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SortOrder::Title => "title",
+            SortOrder::Channel => "channel",
+            SortOrder::Added => "date added",
+            SortOrder::PlayCount => "play count",
+            SortOrder::Duration => "duration"
         }
     }
 
-    while used_ids.contains(&smallest_id) { smallest_id += 1; }
-    Ok(smallest_id)
+    /// ORDER BY clause over `songs` and `playlist_entries`. Ties keep their current order.
+    fn order_by(&self) -> &'static str {
+        match self {
+            SortOrder::Title => "songs.name COLLATE NOCASE",
+            SortOrder::Channel => "songs.channel COLLATE NOCASE, songs.name COLLATE NOCASE",
+            SortOrder::Added => "playlist_entries.added_at, playlist_entries.rowid",
+            SortOrder::PlayCount => "(SELECT COUNT(*) FROM play_history WHERE play_history.song_id = songs.id) DESC",
+            SortOrder::Duration => "songs.duration_ms IS NULL, songs.duration_ms"
+        }
+    }
 }
 
+fn name_error(name: &str, e: rusqlite::Error) -> String {
+    match e.sqlite_error_code() {
+        Some(ErrorCode::ConstraintViolation) => format!("A playlist named {name} already exists"),
+        _ => e.to_string()
+    }
+}
+
+fn check_name(name: &str) -> Result<&str, String> {
+    match name.trim() {
+        "" => Err(String::from("Playlist name cannot be empty")),
+        name => Ok(name)
+    }
+}
+
+/// A removed song, with what is needed to put it back.
+struct Removed {
+    song: Song,
+    playlist_id: i64,
+    position: i64,
+    added_at: i64,
+    /// Deleted along with the song if it left the library.
+    plays: Vec<i64>
+}
+
+/// The current playlist of the library database. Songs are kept in memory in playlist order,
+/// and every change is written through to the database in a single transaction. Songs are shared
+/// between playlists, so a downloaded file is stored once however many playlists it is in.
 pub struct Playlist {
     pub songs: Vec<Song>,
     collected: HashSet<String>,
-    file: PathBuf
+    db: Connection,
+    id: i64,
+    name: String,
+    warning: Option<String>,
+    last_removed: Option<Removed>
 }
 
 impl Playlist {
-    pub fn load_playlist() -> Result<Self, ()> {
-        let filepath: String = get_directory().to_string_lossy().to_string() + "/playlist.txt";
-        let file: PathBuf = PathBuf::from(filepath);
-        let contents = match read_to_string(&file) {
-            Ok(contents) => contents.lines().map(|x| x.to_string()).collect::<Vec<String>>(),
-            Err(_) => {
-                let _ = File::create("playlist.txt");
-                vec![]
-            }
-        };
+    pub fn load_playlist() -> Result<Self, String> {
+        let path = get_directory().join("library.db");
+        let Opened { db, created, warning } = database::open_or_restore(&path)?;
+        let (id, name) = db.query_row("SELECT id, name FROM playlists ORDER BY id LIMIT 1", [], |row| Ok((row.get(0)?, row.get(1)?))).map_err(|e| e.to_string())?;
 
         let mut playlist = Self {
-            songs: contents.into_iter().map(|line| {
-                Song::deserialise(line)
-            }).collect::<Vec<Song>>(),
+            songs: Vec::new(),
             collected: HashSet::new(),
-            file
+            db,
+            id,
+            name,
+            warning,
+            last_removed: None
         };
+        playlist.songs = playlist.query_songs("", []).map_err(|e| e.to_string())?;
 
-        for song in &playlist.songs {
-            playlist.collected.insert(song.url.clone());
+        // The old files are renamed rather than deleted once their songs are safely in the database.
+        if playlist.songs.is_empty() {
+            match read_previous_library()? {
+                Some(songs) => {
+                    playlist.import_songs(songs);
+                    for old in ["library.json", "playlist.txt"] {
+                        let old = get_directory().join(old);
+                        if old.exists() { let _ = rename(&old, old.with_extension(format!("{}.migrated", old.extension().unwrap_or_default().to_string_lossy()))); }
+                    }
+                }
+                None if created => { playlist.import_songs(rebuild_from_tags()); }
+                None => {}
+            }
+        }
+
+        let mut songs = playlist.songs.clone();
+        if migrate_numeric_files(&mut songs) {
+            for song in &songs { playlist.update_song(song); }
         }
 
+        playlist.reindex();
+        let _ = database::backup(&playlist.db, &path);
         Ok(playlist)
     }
 
+    /// A problem found while loading, e.g. that the library had to be restored from a backup.
+    pub fn take_warning(&mut self) -> Option<String> {
+        self.warning.take()
+    }
+
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Every playlist in the library, by name.
+    pub fn playlists(&self) -> Vec<PlaylistInfo> {
+        let mut statement = match self.db.prepare(
+            "SELECT playlists.id, playlists.name, COUNT(playlist_entries.song_id) FROM playlists
+            LEFT JOIN playlist_entries ON playlist_entries.playlist_id = playlists.id
+            GROUP BY playlists.id ORDER BY playlists.name COLLATE NOCASE"
+        ) {
+            Ok(statement) => statement,
+            Err(_) => return Vec::new()
+        };
+        let playlists = statement.query_map([], |row| Ok(PlaylistInfo {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            songs: row.get::<_, i64>(2)? as usize
+        })).map(|rows| rows.flatten().collect()).unwrap_or_default();
+        playlists
+    }
+
+    /// Make another playlist the current one.
+    pub fn switch_to(&mut self, id: i64) -> Result<(), String> {
+        self.name = self.db.query_row("SELECT name FROM playlists WHERE id = ?1", [id], |row| row.get(0)).map_err(|e| e.to_string())?;
+        self.id = id;
+        self.reload()
+    }
+
+    pub fn create_playlist(&mut self, name: &str) -> Result<i64, String> {
+        let name = check_name(name)?;
+        self.db.execute("INSERT INTO playlists (name) VALUES (?1)", [name]).map_err(|e| name_error(name, e))?;
+        Ok(self.db.last_insert_rowid())
+    }
+
+    pub fn rename_playlist(&mut self, id: i64, name: &str) -> Result<(), String> {
+        let name = check_name(name)?;
+        self.db.execute("UPDATE playlists SET name = ?2 WHERE id = ?1", params![id, name]).map_err(|e| name_error(name, e))?;
+        if id == self.id { self.name = name.to_string(); }
+        Ok(())
+    }
+
+    /// Copy a playlist under a new name. The songs themselves are shared, not copied.
+    pub fn duplicate_playlist(&mut self, id: i64, name: &str) -> Result<i64, String> {
+        let name = check_name(name)?;
+        let tx = self.db.transaction().map_err(|e| e.to_string())?;
+        tx.execute("INSERT INTO playlists (name) VALUES (?1)", [name]).map_err(|e| name_error(name, e))?;
+        let copy = tx.last_insert_rowid();
+        tx.execute(
+            "INSERT INTO playlist_entries (playlist_id, song_id, position, added_at)
+            SELECT ?1, song_id, position, added_at FROM playlist_entries WHERE playlist_id = ?2",
+            [copy, id]
+        ).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(copy)
+    }
+
+    /// Delete a playlist, along with any of its songs that are in no other playlist.
+    /// If it was the current playlist, the first remaining one becomes current.
+    pub fn delete_playlist(&mut self, id: i64) -> Result<(), String> {
+        let count: i64 = self.db.query_row("SELECT COUNT(*) FROM playlists", [], |row| row.get(0)).map_err(|e| e.to_string())?;
+        if count <= 1 { return Err(String::from("Cannot delete the only playlist")); }
+
+        let result = (|| -> rusqlite::Result<()> {
+            let tx = self.db.transaction()?;
+            let song_ids = tx.prepare("SELECT song_id FROM playlist_entries WHERE playlist_id = ?1")?
+                .query_map([id], |row| row.get::<_, i64>(0))?
+                .collect::<rusqlite::Result<Vec<i64>>>()?;
+            tx.execute("DELETE FROM playlists WHERE id = ?1", [id])?;
+            for song_id in song_ids {
+                delete_if_unreferenced(&tx, song_id)?;
+            }
+            tx.commit()
+        })();
+        result.map_err(|e| e.to_string())?;
+
+        if id == self.id {
+            let first = self.db.query_row("SELECT id FROM playlists ORDER BY id LIMIT 1", [], |row| row.get(0)).map_err(|e| e.to_string())?;
+            self.switch_to(first)?;
+        }
+        Ok(())
+    }
+
+    /// Add a song to any playlist, returning false if it was already in it.
+    pub fn add_to_playlist(&mut self, id: i64, song: &Song) -> Result<bool, String> {
+        if id == self.id {
+            if self.contains(song) { return Ok(false); }
+            self.add_song(song.clone());
+            return Ok(true);
+        }
+
+        let tx = self.db.transaction().map_err(|e| e.to_string())?;
+        let song_id = insert_song(&tx, song).map_err(|e| e.to_string())?;
+        let added = append_entry(&tx, id, song_id).map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(added > 0)
+    }
+
+    /// Songs of this playlist in order, narrowed down by an extra condition on `songs`.
+    fn query_songs<P: Params>(&self, condition: &str, params: P) -> rusqlite::Result<Vec<Song>> {
+        let sql = format!(
+            "SELECT {SONG_COLUMNS} FROM playlist_entries JOIN songs ON songs.id = playlist_entries.song_id
+            WHERE playlist_entries.playlist_id = {} {condition} ORDER BY playlist_entries.position", self.id
+        );
+        let mut statement = self.db.prepare(&sql)?;
+        let songs = statement.query_map(params, song_from_row)?.collect::<rusqlite::Result<Vec<Song>>>();
+        songs
+    }
+
     pub fn add_song(&mut self, song: Song) {
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&self.file).unwrap();
+        self.import_songs(vec![song]);
+    }
 
-        let _ = writeln!(file, "{}", song.serialise());
+    /// Append songs that are not already in the playlist, returning how many were added.
+    pub fn import_songs(&mut self, songs: Vec<Song>) -> usize {
+        let mut added: Vec<Song> = Vec::new();
+        for song in songs {
+            if self.contains(&song) || added.iter().any(|s| s.key() == song.key()) { continue; }
+            added.push(song);
+        }
 
-        self.collected.insert(song.url.clone());
-        self.songs.push(song);
+        // A song already in the library through another playlist keeps its stored file, rating and edits.
+        let result = (|| -> rusqlite::Result<Vec<Song>> {
+            let tx = self.db.transaction()?;
+            let mut stored: Vec<Song> = Vec::new();
+            for song in &added {
+                let song_id = insert_song(&tx, song)?;
+                append_entry(&tx, self.id, song_id)?;
+                stored.push(song_by_id(&tx, song_id)?);
+            }
+            tx.commit()?;
+            Ok(stored)
+        })();
+        let added = match result {
+            Ok(stored) => stored,
+            Err(_) => return 0
+        };
+
+        let imported = added.len();
+        for song in added {
+            self.collected.insert(song.key());
+            self.songs.push(song);
+        }
+        imported
+    }
+
+    /// Replace the stored copy of a song, e.g. once its download has succeeded.
+    pub fn update_song(&mut self, updated: &Song) {
+        if database::update_song(&self.db, updated).is_err() { return; }
+
+        for song in &mut self.songs {
+            if song.key() == updated.key() { *song = updated.clone(); }
+        }
+    }
+
+    /// Store the result of a finished download, keeping any title, rating or tags changed while it ran.
+    /// Returns the song as now stored.
+    pub fn set_downloaded(&mut self, downloaded: &Song) -> Option<Song> {
+        database::update_download(&self.db, downloaded).ok()?;
+        let stored = song_id(&self.db, downloaded).ok()?.and_then(|song_id| song_by_id(&self.db, song_id).ok())?;
+
+        for song in &mut self.songs {
+            if song.key() == stored.key() { *song = stored.clone(); }
+        }
+        Some(stored)
+    }
+
+    /// Save a song's edited title, artist and album. A downloaded file has its tags rewritten to match,
+    /// unless it holds several songs as chapters or was imported from outside the data directory.
+    pub fn edit_song(&mut self, edited: &Song) -> Result<(), String> {
+        database::update_song(&self.db, edited).map_err(|e| format!("Failed to save {}: {e}", edited.name))?;
+        for song in &mut self.songs {
+            if song.key() == edited.key() { *song = edited.clone(); }
+        }
+
+        match edited.file {
+            Some(ref file) if edited.chapter.is_none() && is_managed(file) => write_tags(file, edited, None),
+            _ => Ok(())
+        }
+    }
+
+    /// Replace a song with one entry per chapter, each playing its own range of the same file.
+    pub fn split_song(&mut self, song: &Song, chapters: &[Chapter]) -> Result<(), String> {
+        let idx = match self.songs.iter().position(|s| s.key() == song.key()) {
+            Some(idx) => idx,
+            None => return Err(format!("{} is not in playlist {}", song.name, self.name))
+        };
+
+        let last = chapters.len().saturating_sub(1);
+        let tracks = chapters.iter().enumerate().map(|(n, chapter)| {
+            let mut track = song.clone();
+            track.name = chapter.title.clone();
+            track.chapter = Some(n);
+            // Keep the silence trimmed from the start of the first and end of the last chapter.
+            track.start = Some(if n == 0 { chapter.start.max(song.start.unwrap_or_default()) } else { chapter.start });
+            track.end = match song.end {
+                Some(end) if n == last => Some(chapter.end.min(end)),
+                _ => Some(chapter.end)
+            };
+            track
+        }).collect::<Vec<Song>>();
+
+        let result = (|| -> rusqlite::Result<()> {
+            let tx = self.db.transaction()?;
+            let song_id = match song_id(&tx, song)? {
+                Some(song_id) => song_id,
+                None => return Err(rusqlite::Error::QueryReturnedNoRows)
+            };
+            let position: i64 = tx.query_row("SELECT position FROM playlist_entries WHERE playlist_id = ?1 AND song_id = ?2", [self.id, song_id], |row| row.get(0))?;
+
+            tx.execute("DELETE FROM playlist_entries WHERE playlist_id = ?1 AND song_id = ?2", [self.id, song_id])?;
+            delete_if_unreferenced(&tx, song_id)?;
+            tx.execute("UPDATE playlist_entries SET position = position + ?1 WHERE playlist_id = ?2 AND position > ?3", params![tracks.len() as i64 - 1, self.id, position])?;
+
+            for (n, track) in tracks.iter().enumerate() {
+                let track_id = insert_song(&tx, track)?;
+                tx.execute("INSERT OR IGNORE INTO playlist_entries (playlist_id, song_id, position, added_at) VALUES (?1, ?2, ?3, ?4)", params![self.id, track_id, position + n as i64, now()])?;
+            }
+            tx.commit()
+        })();
+        result.map_err(|e| format!("Failed to split {}: {e}", song.name))?;
+
+        self.songs.splice(idx..=idx, tracks);
+        self.reindex();
+        Ok(())
+    }
+
+    /// Swap a song with its neighbour above or below, returning its new index.
+    pub fn move_song(&mut self, idx: usize, up: bool) -> Option<usize> {
+        let other = if up { idx.checked_sub(1)? } else { idx + 1 };
+        if other >= self.songs.len() || idx >= self.songs.len() { return None; }
+
+        let result = (|| -> rusqlite::Result<()> {
+            let tx = self.db.transaction()?;
+            let (a, b) = match (song_id(&tx, &self.songs[idx])?, song_id(&tx, &self.songs[other])?) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return Err(rusqlite::Error::QueryReturnedNoRows)
+            };
+            let position = |song_id: i64| tx.query_row(
+                "SELECT position FROM playlist_entries WHERE playlist_id = ?1 AND song_id = ?2", [self.id, song_id], |row| row.get::<_, i64>(0)
+            );
+            let (position_a, position_b) = (position(a)?, position(b)?);
+            tx.execute("UPDATE playlist_entries SET position = ?3 WHERE playlist_id = ?1 AND song_id = ?2", [self.id, a, position_b])?;
+            tx.execute("UPDATE playlist_entries SET position = ?3 WHERE playlist_id = ?1 AND song_id = ?2", [self.id, b, position_a])?;
+            tx.commit()
+        })();
+        if result.is_err() { return None; }
+
+        self.songs.swap(idx, other);
+        Some(other)
+    }
+
+    /// Reorder the playlist, storing the new order as its positions.
+    pub fn sort(&mut self, order: SortOrder) -> Result<(), String> {
+        // Durations are only known for songs downloaded or imported since they were recorded.
+        if order == SortOrder::Duration {
+            for song in self.songs.clone() {
+                let duration = match song.file {
+                    Some(ref file) if song.duration.is_none() => read_duration(file),
+                    _ => None
+                };
+                if duration.is_some() { self.update_song(&Song { duration, ..song }); }
+            }
+        }
+
+        let result = (|| -> rusqlite::Result<()> {
+            let tx = self.db.transaction()?;
+            let song_ids = tx.prepare(&format!(
+                "SELECT songs.id FROM playlist_entries JOIN songs ON songs.id = playlist_entries.song_id
+                WHERE playlist_entries.playlist_id = ?1 ORDER BY {}, playlist_entries.position", order.order_by()
+            ))?.query_map([self.id], |row| row.get::<_, i64>(0))?.collect::<rusqlite::Result<Vec<i64>>>()?;
+
+            for (position, song_id) in song_ids.into_iter().enumerate() {
+                tx.execute("UPDATE playlist_entries SET position = ?3 WHERE playlist_id = ?1 AND song_id = ?2", [self.id, song_id, position as i64])?;
+            }
+            tx.commit()
+        })();
+        result.map_err(|e| e.to_string())?;
+
+        self.songs = self.query_songs("", []).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Read the songs of this playlist back from the database after changes made through the library.
+    fn reload(&mut self) -> Result<(), String> {
+        self.songs = self.query_songs("", []).map_err(|e| e.to_string())?;
+        self.reindex();
+        Ok(())
+    }
+
+    /// Every song in the library, whichever playlists it is in.
+    pub fn library_songs(&self) -> Vec<Song> {
+        let mut statement = match self.db.prepare(&format!("SELECT {SONG_COLUMNS} FROM songs ORDER BY songs.id")) {
+            Ok(statement) => statement,
+            Err(_) => return Vec::new()
+        };
+        let songs = statement.query_map([], song_from_row).map(|rows| rows.flatten().collect()).unwrap_or_default();
+        songs
+    }
+
+    /// Remove a song from every playlist at once.
+    pub fn remove_from_library(&mut self, song: &Song) -> Result<(), String> {
+        self.db.execute("DELETE FROM songs WHERE key = ?1", [song.key()]).map_err(|e| e.to_string())?;
+        self.reload()
+    }
+
+    /// Fold duplicates of a song into it: their playlist entries and play history move to the kept song.
+    pub fn merge_songs(&mut self, keep: &Song, duplicates: &[Song]) -> Result<(), String> {
+        let result = (|| -> rusqlite::Result<()> {
+            let tx = self.db.transaction()?;
+            let keep_id = match song_id(&tx, keep)? {
+                Some(keep_id) => keep_id,
+                None => return Ok(())
+            };
+            for duplicate in duplicates {
+                let duplicate_id = match song_id(&tx, duplicate)? {
+                    Some(duplicate_id) if duplicate_id != keep_id => duplicate_id,
+                    _ => continue
+                };
+                tx.execute(
+                    "INSERT OR IGNORE INTO playlist_entries (playlist_id, song_id, position, added_at)
+                    SELECT playlist_id, ?1, position, added_at FROM playlist_entries WHERE song_id = ?2",
+                    [keep_id, duplicate_id]
+                )?;
+                tx.execute("UPDATE play_history SET song_id = ?1 WHERE song_id = ?2", [keep_id, duplicate_id])?;
+                tx.execute("DELETE FROM songs WHERE id = ?1", [duplicate_id])?;
+            }
+            tx.commit()
+        })();
+        result.map_err(|e| e.to_string())?;
+        self.reload()
+    }
+
+    /// Rebuild the lookup used by `contains`. Chapters also mark their whole video as collected.
+    fn reindex(&mut self) {
+        self.collected.clear();
+        for song in &self.songs {
+            self.collected.insert(song.key());
+            if let Some(ref url) = song.url { self.collected.insert(url.clone()); }
+        }
     }
 
     pub fn contains(&self, song: &Song) -> bool {
-        return self.collected.contains(&song.url);
+        self.collected.contains(&song.key())
+    }
+
+    /// Whether any playlist has the song.
+    pub fn in_library(&self, song: &Song) -> bool {
+        song_id(&self.db, song).is_ok_and(|song_id| song_id.is_some())
     }
 
-    pub fn remove_song(&mut self, idx: usize) {
-        self.songs.remove(idx);
-        
-        let mut contents = match read_to_string(&self.file) {
-            Ok(contents) => contents.lines().map(|x| x.to_string()).collect::<Vec<String>>(),
-            Err(_) => return
+    /// Remove a song from this playlist, remembering it so the removal can be undone.
+    /// The song leaves the library too if no other playlist has it.
+    pub fn remove_song(&mut self, song: &Song) -> Result<(), String> {
+        let result = (|| -> rusqlite::Result<Option<Removed>> {
+            let tx = self.db.transaction()?;
+            let song_id = match song_id(&tx, song)? {
+                Some(song_id) => song_id,
+                None => return Ok(None)
+            };
+            let (position, added_at) = match tx.query_row(
+                "SELECT position, added_at FROM playlist_entries WHERE playlist_id = ?1 AND song_id = ?2",
+                [self.id, song_id], |row| Ok((row.get(0)?, row.get(1)?))
+            ).optional()? {
+                Some(entry) => entry,
+                None => return Ok(None)
+            };
+
+            let stored = tx.query_row(&format!("SELECT {SONG_COLUMNS} FROM songs WHERE id = ?1"), [song_id], song_from_row)?;
+            let plays = tx.prepare("SELECT played_at FROM play_history WHERE song_id = ?1")?
+                .query_map([song_id], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<i64>>>()?;
+
+            tx.execute("DELETE FROM playlist_entries WHERE playlist_id = ?1 AND song_id = ?2", [self.id, song_id])?;
+            delete_if_unreferenced(&tx, song_id)?;
+            tx.commit()?;
+
+            Ok(Some(Removed { song: stored, playlist_id: self.id, position, added_at, plays }))
+        })();
+
+        match result {
+            Ok(Some(removed)) => self.last_removed = Some(removed),
+            Ok(None) => return Err(format!("{} is not in this playlist", song.name)),
+            Err(e) => return Err(e.to_string())
+        }
+
+        self.songs.retain(|s| s.key() != song.key());
+        self.reindex();
+        Ok(())
+    }
+
+    /// Put the last removed song back where it was. Its play history is restored if it had left the library.
+    pub fn undo_remove(&mut self) -> Result<Song, String> {
+        let removed = match self.last_removed.take() {
+            Some(removed) => removed,
+            None => return Err(String::from("Nothing to undo"))
         };
 
-        contents.remove(idx);
+        let result = (|| -> rusqlite::Result<()> {
+            let tx = self.db.transaction()?;
+            let existed = song_id(&tx, &removed.song)?.is_some();
+            let song_id = insert_song(&tx, &removed.song)?;
 
-        let mut file = File::create(&self.file).unwrap();
+            tx.execute("UPDATE playlist_entries SET position = position + 1 WHERE playlist_id = ?1 AND position >= ?2", [removed.playlist_id, removed.position])?;
+            tx.execute(
+                "INSERT OR IGNORE INTO playlist_entries (playlist_id, song_id, position, added_at) VALUES (?1, ?2, ?3, ?4)",
+                [removed.playlist_id, song_id, removed.position, removed.added_at]
+            )?;
+            if !existed {
+                for played_at in &removed.plays {
+                    tx.execute("INSERT INTO play_history (song_id, played_at) VALUES (?1, ?2)", [song_id, *played_at])?;
+                }
+            }
+            tx.commit()
+        })();
+        result.map_err(|e| format!("Could not undo removal of {}: {e}", removed.song.name))?;
 
-        for line in contents {
-            let _ = writeln!(file, "{line}");
+        if removed.playlist_id == self.id { self.reload()?; }
+        Ok(removed.song)
+    }
+
+    /// Delete the downloaded file of a removed song. Files outside the data directory, e.g. imported
+    /// from the user's own music, and files still used by another song are never deleted.
+    pub fn delete_file(&mut self, song: &Song) -> Result<(), String> {
+        let file = match song.file {
+            Some(ref file) => file,
+            None => return Err(String::from("it was not downloaded"))
+        };
+        if !is_managed(file) {
+            return Err(String::from("it was imported from outside the rmusic data directory"));
+        }
+
+        let references: i64 = self.db.query_row("SELECT COUNT(*) FROM songs WHERE file = ?1", [file.to_string_lossy()], |row| row.get(0)).map_err(|e| e.to_string())?;
+        if references > 0 {
+            return Err(String::from("another song still uses it"));
+        }
+
+        remove_file(file).map_err(|e| e.to_string())?;
+
+        // Undoing the removal brings the song back as not downloaded.
+        if let Some(removed) = self.last_removed.as_mut().filter(|removed| removed.song.key() == song.key()) {
+            removed.song.file = None;
         }
+        Ok(())
+    }
+
+    /// Songs with a URL that have not been downloaded yet.
+    pub fn not_downloaded(&self) -> Vec<Song> {
+        self.query_songs("AND songs.file IS NULL AND songs.url IS NOT NULL", []).unwrap_or_default()
+    }
+
+    /// Songs from any playlist, most played first.
+    pub fn most_played(&self, limit: usize) -> Vec<Song> {
+        let sql = format!(
+            "SELECT {SONG_COLUMNS} FROM play_history JOIN songs ON songs.id = play_history.song_id
+            GROUP BY songs.id ORDER BY COUNT(*) DESC, MAX(play_history.played_at) DESC LIMIT ?1"
+        );
+        let mut statement = match self.db.prepare(&sql) {
+            Ok(statement) => statement,
+            Err(_) => return Vec::new()
+        };
+        let songs = statement.query_map([limit as i64], song_from_row).map(|rows| rows.flatten().collect()).unwrap_or_default();
+        songs
+    }
+
+    /// Note that a song started playing. Songs that are not in the library, e.g. previews, are ignored.
+    pub fn record_play(&self, song: &Song) {
+        let _ = self.db.execute("INSERT INTO play_history (song_id, played_at) SELECT id, ?2 FROM songs WHERE key = ?1", params![song.key(), now()]);
+    }
+
+    pub fn set_download_state(&self, song: &Song, state: &DownloadState) {
+        let (state, error) = download_state_label(state);
+        let _ = self.db.execute(
+            "INSERT INTO download_state (song_id, state, error, updated_at) SELECT id, ?2, ?3, ?4 FROM songs WHERE key = ?1
+            ON CONFLICT (song_id) DO UPDATE SET state = excluded.state, error = excluded.error, updated_at = excluded.updated_at",
+            params![song.key(), state, error, now()]
+        );
+    }
+
+    /// Songs whose download was queued but never finished, e.g. because the app was closed.
+    pub fn interrupted_downloads(&self) -> Vec<Song> {
+        self.query_songs("AND songs.file IS NULL AND songs.id IN (SELECT song_id FROM download_state WHERE state = 'queued')", []).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(names: &[&str]) -> Playlist {
+        let db = database::open(Path::new(":memory:")).unwrap();
+        let mut playlist = Playlist {
+            songs: Vec::new(),
+            collected: HashSet::new(),
+            db,
+            id: 1,
+            name: String::from("Library"),
+            warning: None,
+            last_removed: None
+        };
+        playlist.import_songs(names.iter().map(|name| Song { name: name.to_string(), url: Some(format!("https://youtu.be/{name}")), ..Default::default() }).collect());
+        playlist
+    }
+
+    fn stored_names(playlist: &Playlist) -> Vec<String> {
+        playlist.query_songs("", []).unwrap().into_iter().map(|song| song.name).collect()
+    }
+
+    #[test]
+    fn import_songs_keeps_library_copy() {
+        let mut playlist = playlist(&["a"]);
+        let stored = Song { file: Some(PathBuf::from("/music/a.mp3")), rating: Some(5), tags: vec![String::from("chill")], ..playlist.songs[0].clone() };
+        playlist.update_song(&stored);
+        let other = playlist.create_playlist("Other").unwrap();
+        playlist.switch_to(other).unwrap();
+
+        playlist.add_song(Song { name: String::from("a (search result)"), url: stored.url.clone(), ..Default::default() });
+        assert!(playlist.songs[0] == stored);
+    }
+
+    #[test]
+    fn set_downloaded_keeps_edits() {
+        let mut playlist = playlist(&["a"]);
+        let queued = playlist.songs[0].clone();
+        playlist.update_song(&Song { name: String::from("edited"), rating: Some(4), ..queued.clone() });
+
+        let downloaded = Song { file: Some(PathBuf::from("/music/a.mp3")), ..queued };
+        let stored = playlist.set_downloaded(&downloaded).unwrap();
+        assert_eq!((stored.name.as_str(), stored.rating, stored.file.clone()), ("edited", Some(4), downloaded.file));
+        assert!(playlist.songs[0] == stored);
+    }
+
+    #[test]
+    fn move_song_stores_swapped_positions() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+
+        assert_eq!(playlist.move_song(0, false), Some(1));
+        assert_eq!(stored_names(&playlist), ["b", "a", "c"]);
+        assert_eq!(playlist.move_song(2, true), Some(1));
+        assert_eq!(stored_names(&playlist), ["b", "c", "a"]);
+        assert_eq!(playlist.move_song(0, true), None);
     }
 }
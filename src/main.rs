@@ -3,13 +3,35 @@ mod application;
 mod filemanager;
 mod downloader;
 mod audio;
+mod cache;
+mod config;
+mod tags;
+mod processing;
+mod database;
+mod playlistfile;
+mod doctor;
 
 use crate::application::Application;
 
 #[tokio::main]
 async fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args.first().is_some_and(|command| command == "doctor") {
+        if let Err(e) = doctor::run_cli(args.iter().any(|arg| arg == "--fix")) {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut application = match Application::new() {
+        Ok(application) => application,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
     let mut terminal = ratatui::init();
-    let mut application: Application = Application::new();
     application.run(&mut terminal).await;
     ratatui::restore();
 }
@@ -0,0 +1,240 @@
+use std::fs::read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::downloader::Song;
+use crate::filemanager::write_atomic;
+use crate::tags::read_tags;
+
+/// Playlist file formats shared with other players, chosen by file extension.
+#[derive(PartialEq, Eq)]
+enum Format {
+    M3u,
+    Xspf
+}
+
+fn format_of(path: &Path) -> Result<Format, String> {
+    match path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).as_deref() {
+        Some("m3u") | Some("m3u8") => Ok(Format::M3u),
+        Some("xspf") => Ok(Format::Xspf),
+        _ => Err(format!("{} is not an .m3u, .m3u8 or .xspf file", path.to_string_lossy()))
+    }
+}
+
+/// Percent-encode a path as a file:// URI, as XSPF locations must be URIs.
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{byte:02X}"))
+        }
+    }
+    uri
+}
+
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let hex = encoded.get(idx + 1..idx + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[idx], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                idx += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Where a song can be found by another player: its downloaded file, or failing that its URL.
+fn location(song: &Song) -> Option<String> {
+    match (&song.file, &song.url) {
+        (Some(file), _) => Some(file.to_string_lossy().to_string()),
+        (None, Some(url)) => Some(url.clone()),
+        (None, None) => None
+    }
+}
+
+/// "Channel - Title", the usual M3U convention for the display name.
+fn display_name(song: &Song) -> String {
+    match song.channel.is_empty() {
+        true => song.name.clone(),
+        false => format!("{} - {}", song.channel, song.name)
+    }
+}
+
+/// Write the songs to an M3U8 or XSPF file, depending on its extension, returning how many were written.
+pub fn export(songs: &[Song], title: &str, path: &Path) -> Result<usize, String> {
+    let songs = songs.iter().filter(|song| location(song).is_some()).collect::<Vec<&Song>>();
+    let contents = match format_of(path)? {
+        Format::M3u => export_m3u(&songs),
+        Format::Xspf => export_xspf(&songs, title)
+    };
+
+    write_atomic(path, contents.as_bytes()).map_err(|e| format!("Failed to write {}: {e}", path.to_string_lossy()))?;
+    Ok(songs.len())
+}
+
+fn export_m3u(songs: &[&Song]) -> String {
+    let mut contents = String::from("#EXTM3U\n");
+    for song in songs {
+        let seconds = song.duration.map(|duration| duration.as_secs() as i64).unwrap_or(-1);
+        contents.push_str(&format!("#EXTINF:{seconds},{}\n", display_name(song).replace('\n', " ")));
+        // VLC's options for playing only part of a file, e.g. a chapter of a full album upload.
+        if song.file.is_some() {
+            if let Some(start) = song.start { contents.push_str(&format!("#EXTVLCOPT:start-time={:.3}\n", start.as_secs_f64())); }
+            if let Some(end) = song.end { contents.push_str(&format!("#EXTVLCOPT:stop-time={:.3}\n", end.as_secs_f64())); }
+        }
+        contents.push_str(&location(song).unwrap_or_default());
+        contents.push('\n');
+    }
+    contents
+}
+
+fn export_xspf(songs: &[&Song], title: &str) -> String {
+    let mut contents = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    contents.push_str(&format!("  <title>{}</title>\n  <trackList>\n", escape(title)));
+    for song in songs {
+        let location = match song.file {
+            Some(ref file) => file_uri(file),
+            None => location(song).unwrap_or_default()
+        };
+        contents.push_str("    <track>\n");
+        contents.push_str(&format!("      <location>{}</location>\n", escape(&location)));
+        contents.push_str(&format!("      <title>{}</title>\n", escape(&song.name)));
+        if !song.channel.is_empty() { contents.push_str(&format!("      <creator>{}</creator>\n", escape(&song.channel))); }
+        if let Some(duration) = song.duration { contents.push_str(&format!("      <duration>{}</duration>\n", duration.as_millis())); }
+        contents.push_str("    </track>\n");
+    }
+    contents.push_str("  </trackList>\n</playlist>\n");
+    contents
+}
+
+/// A playlist entry as read from the file, before it is turned into a song.
+#[derive(Default)]
+struct Entry {
+    location: String,
+    title: Option<String>,
+    creator: Option<String>,
+    duration: Option<Duration>
+}
+
+/// Read an M3U, M3U8 or XSPF file into songs. Local entries that cannot be found are left out;
+/// the second value is how many entries the file had in total.
+pub fn import(path: &Path) -> Result<(Vec<Song>, usize), String> {
+    let bytes = read(path).map_err(|e| format!("Failed to read {}: {e}", path.to_string_lossy()))?;
+    let contents = String::from_utf8_lossy(&bytes);
+    let entries = match format_of(path)? {
+        Format::M3u => import_m3u(&contents),
+        Format::Xspf => import_xspf(&contents)?
+    };
+
+    let base = path.parent().unwrap_or(Path::new("."));
+    let total = entries.len();
+    Ok((entries.into_iter().filter_map(|entry| to_song(entry, base)).collect(), total))
+}
+
+fn import_m3u(contents: &str) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut entry = Entry::default();
+
+    for line in contents.lines().map(|line| line.trim_start_matches('\u{feff}').trim()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<artist> - <title>
+            let (seconds, name) = info.split_once(',').unwrap_or((info, ""));
+            entry.duration = seconds.trim().parse::<f64>().ok().filter(|seconds| *seconds > 0.0).map(Duration::from_secs_f64);
+            match name.split_once(" - ") {
+                Some((creator, title)) => {
+                    entry.creator = Some(creator.trim().to_string());
+                    entry.title = Some(title.trim().to_string());
+                }
+                None if !name.trim().is_empty() => entry.title = Some(name.trim().to_string()),
+                None => {}
+            }
+        } else if !line.is_empty() && !line.starts_with('#') {
+            entry.location = line.to_string();
+            entries.push(std::mem::take(&mut entry));
+        }
+    }
+
+    entries
+}
+
+fn import_xspf(contents: &str) -> Result<Vec<Entry>, String> {
+    let mut reader = Reader::from_str(contents);
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut element: Vec<u8> = Vec::new();
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(start)) => {
+                element = start.local_name().as_ref().to_vec();
+                if element == b"track" { entry = Some(Entry::default()); }
+            }
+            Ok(Event::End(end)) => {
+                if end.local_name().as_ref() == b"track" {
+                    if let Some(entry) = entry.take().filter(|entry| !entry.location.is_empty()) { entries.push(entry); }
+                }
+                element.clear();
+            }
+            Ok(Event::Text(text)) => {
+                let (entry, text) = match (entry.as_mut(), text.unescape()) {
+                    (Some(entry), Ok(text)) => (entry, text.trim().to_string()),
+                    _ => continue
+                };
+                match element.as_slice() {
+                    // Only the first location is used if a track lists alternatives.
+                    b"location" if entry.location.is_empty() => entry.location = text,
+                    b"title" => entry.title = Some(text),
+                    b"creator" => entry.creator = Some(text),
+                    b"duration" => entry.duration = text.parse::<u64>().ok().map(Duration::from_millis),
+                    _ => {}
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => return Err(format!("Invalid XSPF at byte {}: {e}", reader.buffer_position()))
+        }
+    }
+
+    Ok(entries)
+}
+
+/// URLs become streamable songs. Local files are read for their tags, with the playlist's own
+/// title and artist filling in anything the tags are missing.
+fn to_song(entry: Entry, base: &Path) -> Option<Song> {
+    if entry.location.starts_with("http://") || entry.location.starts_with("https://") {
+        return Some(Song {
+            name: entry.title.unwrap_or(entry.location.clone()),
+            channel: entry.creator.unwrap_or_default(),
+            url: Some(entry.location),
+            duration: entry.duration,
+            ..Default::default()
+        });
+    }
+
+    let path = match entry.location.strip_prefix("file://") {
+        Some(uri) => PathBuf::from(percent_decode(uri)),
+        None => base.join(&entry.location)
+    };
+    let path = path.canonicalize().ok()?;
+
+    let mut song = read_tags(&path)?;
+    let untitled = path.file_stem().is_some_and(|stem| stem.to_string_lossy() == song.name);
+    if let Some(title) = entry.title.filter(|_| untitled) { song.name = title; }
+    if let Some(creator) = entry.creator.filter(|_| song.channel.is_empty()) { song.channel = creator; }
+    if song.duration.is_none() { song.duration = entry.duration; }
+    Some(song)
+}
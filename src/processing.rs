@@ -0,0 +1,184 @@
+use std::fs::{read_to_string, remove_file, File};
+use std::io::BufReader;
+use std::time::Duration;
+use std::path::Path;
+
+use rodio::{Decoder, Source};
+use serde::Deserialize;
+
+use crate::config::ProcessingConfig;
+use crate::downloader::Song;
+
+// Samples quieter than this (out of i16::MAX) count as silence.
+const SILENCE_THRESHOLD: i16 = 328;
+// Shorter stretches of silence are left alone.
+const MIN_SILENCE: Duration = Duration::from_millis(500);
+
+/// SponsorBlock categories that are not part of the music.
+pub const NON_MUSIC_CATEGORIES: &str = "music_offtopic,sponsor,selfpromo,interaction";
+
+#[derive(Deserialize)]
+struct InfoJson {
+    #[serde(default)]
+    sponsorblock_chapters: Vec<SponsorBlockSegment>,
+    #[serde(default)]
+    chapters: Option<Vec<InfoChapter>>,
+    #[serde(default)]
+    duration: Option<f64>
+}
+
+#[derive(Deserialize)]
+struct InfoChapter {
+    start_time: f64,
+    end_time: f64,
+    #[serde(default)]
+    title: String
+}
+
+/// A chapter marker of a downloaded video, e.g. one track of a full album upload.
+#[derive(Clone)]
+pub struct Chapter {
+    pub title: String,
+    pub start: Duration,
+    pub end: Duration
+}
+
+#[derive(Deserialize)]
+struct SponsorBlockSegment {
+    start_time: f64,
+    end_time: f64,
+    category: String
+}
+
+/// Work out which parts of a freshly downloaded file should be played, storing the result as
+/// trim points and skipped segments on the song so the file itself never needs re-downloading.
+/// Returns the video's chapters if it has more than one, so the caller can offer to split it.
+/// A song that is already one chapter of the video keeps the range it was split with.
+pub fn process(song: &mut Song, output: &Path, processing: &ProcessingConfig) -> Vec<Chapter> {
+    let chapter = song.chapter.is_some();
+    let info_json = output.with_extension("info.json");
+    let info = read_to_string(&info_json).ok().and_then(|contents| serde_json::from_str::<InfoJson>(&contents).ok());
+
+    if let Some(duration) = info.as_ref().and_then(|info| info.duration) {
+        song.duration = Some(Duration::from_secs_f64(duration));
+    }
+
+    if processing.trim_silence && !chapter {
+        if let Some((start, end)) = find_silence(output) {
+            song.start = start;
+            song.end = end;
+        }
+    }
+
+    if processing.skip_non_music {
+        if let Some(ref info) = info {
+            song.skips = info.sponsorblock_chapters.iter()
+                .filter(|segment| NON_MUSIC_CATEGORIES.split(',').any(|category| category == segment.category))
+                .map(|segment| (Duration::from_secs_f64(segment.start_time), Duration::from_secs_f64(segment.end_time)))
+                .collect();
+        }
+    }
+
+    let _ = remove_file(&info_json);
+    if chapter { return Vec::new(); }
+
+    let chapters = info.and_then(|info| info.chapters).unwrap_or_default().into_iter().enumerate().map(|(n, chapter)| Chapter {
+        title: if chapter.title.is_empty() { format!("{} ({})", song.name, n + 1) } else { chapter.title },
+        start: Duration::from_secs_f64(chapter.start_time),
+        end: Duration::from_secs_f64(chapter.end_time)
+    }).collect::<Vec<Chapter>>();
+
+    if chapters.len() > 1 { chapters } else { Vec::new() }
+}
+
+/// Decode the file and find where the audio starts and stops, returning trim points for
+/// leading and trailing silence that is long enough to be worth skipping.
+fn find_silence(path: &Path) -> Option<(Option<Duration>, Option<Duration>)> {
+    let decoder = Decoder::new(BufReader::new(File::open(path).ok()?)).ok()?;
+    let channels = decoder.channels() as u64;
+    let sample_rate = decoder.sample_rate() as u64;
+    if channels == 0 || sample_rate == 0 { return None; }
+
+    let mut first_loud: Option<u64> = None;
+    let mut last_loud: u64 = 0;
+    let mut total: u64 = 0;
+
+    for (idx, sample) in decoder.enumerate() {
+        if sample.unsigned_abs() > SILENCE_THRESHOLD as u16 {
+            if first_loud.is_none() { first_loud = Some(idx as u64); }
+            last_loud = idx as u64;
+        }
+        total = idx as u64 + 1;
+    }
+
+    let to_duration = |sample: u64| Duration::from_millis(sample / channels * 1000 / sample_rate);
+    let start = to_duration(first_loud?);
+    let end = to_duration(last_loud + 1);
+    let length = to_duration(total);
+
+    Some((
+        if start >= MIN_SILENCE { Some(start) } else { None },
+        if length.saturating_sub(end) >= MIN_SILENCE { Some(end) } else { None }
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use super::process;
+    use crate::config::ProcessingConfig;
+    use crate::downloader::Song;
+
+    const RATE: u32 = 8000;
+
+    /// A mono 16 bit WAV of one second of silence, one second of tone and one second of silence,
+    /// along with an info.json listing two chapters.
+    fn download(directory: &Path) -> PathBuf {
+        create_dir_all(directory).unwrap();
+        let samples = (0..RATE * 3).map(|n| if (RATE..RATE * 2).contains(&n) { if n % 16 < 8 { 8000i16 } else { -8000 } } else { 0 }).collect::<Vec<i16>>();
+        let data = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect::<Vec<u8>>();
+
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&RATE.to_le_bytes());
+        wav.extend_from_slice(&(RATE * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+
+        let output = directory.join("video.wav");
+        write(&output, wav).unwrap();
+        write(output.with_extension("info.json"), r#"{"chapters": [
+            {"start_time": 0.0, "end_time": 1.5, "title": "One"},
+            {"start_time": 1.5, "end_time": 3.0, "title": "Two"}
+        ]}"#).unwrap();
+        output
+    }
+
+    #[test]
+    fn process_keeps_chapter_range() {
+        let processing = ProcessingConfig { trim_silence: true, skip_non_music: false };
+        let output = download(&std::env::temp_dir().join(format!("rmusic-process-{}", std::process::id())));
+
+        let mut whole = Song { name: String::from("Album"), ..Default::default() };
+        assert_eq!(process(&mut whole, &output, &processing).len(), 2);
+        assert_eq!(whole.start, Some(Duration::from_secs(1)));
+
+        download(output.parent().unwrap());
+        let range = (Some(Duration::from_millis(1500)), Some(Duration::from_secs(3)));
+        let mut chapter = Song { name: String::from("Two"), chapter: Some(1), start: range.0, end: range.1, ..Default::default() };
+        assert!(process(&mut chapter, &output, &processing).is_empty());
+        assert_eq!((chapter.start, chapter.end), range);
+        let _ = remove_dir_all(output.parent().unwrap());
+    }
+}
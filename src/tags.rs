@@ -0,0 +1,153 @@
+use std::path::Path;
+use std::fs::read;
+use std::time::Duration;
+
+use lofty::config::WriteOptions;
+use lofty::picture::{MimeType, Picture, PictureType};
+use lofty::prelude::*;
+use lofty::tag::Tag;
+
+use crate::downloader::Song;
+
+/// Write the song's title, channel (as artist) and source URL into the file's tags,
+/// embedding the cover image if one is given.
+pub fn write_tags(path: &Path, song: &Song, cover: Option<&Path>) -> Result<(), String> {
+    let mut tagged_file = lofty::read_from_path(path).map_err(|e| format!("Failed to read tags of {}: {e}", path.to_string_lossy()))?;
+
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+
+    let tag = match tagged_file.primary_tag_mut() {
+        Some(tag) => tag,
+        None => return Err(String::from("File format does not support tags"))
+    };
+
+    tag.set_title(song.name.clone());
+    tag.set_artist(song.channel.clone());
+    match song.album.is_empty() {
+        true => tag.remove_album(),
+        false => tag.set_album(song.album.clone())
+    }
+    if let Some(ref url) = song.url {
+        tag.insert_text(ItemKey::AudioSourceUrl, url.clone());
+    }
+
+    if let Some(data) = cover.and_then(|cover| read(cover).ok()) {
+        tag.remove_picture_type(PictureType::CoverFront);
+        tag.push_picture(Picture::unchecked(data).pic_type(PictureType::CoverFront).mime_type(MimeType::Jpeg).build());
+    }
+
+    tagged_file.save_to_path(path, WriteOptions::default()).map_err(|e| format!("Failed to write tags to {}: {e}", path.to_string_lossy()))
+}
+
+/// Build a song from the tags of an audio file, falling back to the file name for the title.
+pub fn read_tags(path: &Path) -> Option<Song> {
+    let tagged_file = lofty::read_from_path(path).ok()?;
+    let tag = tagged_file.primary_tag().or(tagged_file.first_tag());
+
+    let name = match tag.and_then(|tag| tag.title()) {
+        Some(title) => title.to_string(),
+        None => path.file_stem()?.to_string_lossy().to_string()
+    };
+
+    Some(Song {
+        name,
+        channel: tag.and_then(|tag| tag.artist()).map(|artist| artist.to_string()).unwrap_or_default(),
+        url: tag.and_then(|tag| tag.get_string(ItemKey::AudioSourceUrl)).map(|url| url.to_string()),
+        file: Some(path.to_path_buf()),
+        duration: Some(tagged_file.properties().duration()).filter(|duration| !duration.is_zero()),
+        album: tag.and_then(|tag| tag.album()).map(|album| album.to_string()).unwrap_or_default(),
+        ..Default::default()
+    })
+}
+
+// Words that can describe the upload rather than the song. A bracketed group or a tail after a separator
+// made up of nothing else is dropped, but only if it also has a marker that is never part of a real title.
+const TITLE_NOISE: [&str; 20] = [
+    "official", "music", "video", "audio", "lyric", "lyrics", "with", "hd", "hq", "4k",
+    "1080p", "720p", "visualizer", "visualiser", "mv", "m/v", "clip", "videoclip", "explicit", "version"
+];
+
+// Markers that on their own show a group is about the upload. "official" only counts alongside another word,
+// e.g. "Official Video".
+const STRONG_NOISE: [&str; 11] = ["lyric", "lyrics", "visualizer", "visualiser", "hd", "hq", "4k", "1080p", "720p", "mv", "m/v"];
+
+// Never the last word of a real title, so dropped from the end even without a separator.
+const BARE_NOISE: [&str; 6] = ["hd", "hq", "4k", "1080p", "720p", "mv"];
+
+/// Strip upload noise such as "(Official Video)", "[Lyrics]" or a trailing "HD" from a video title.
+/// Brackets with anything else in them, e.g. "(feat. ...)" or "(Live)", are kept.
+pub fn clean_title(title: &str) -> String {
+    let is_noise = |text: &str| {
+        let words = text.split(|c: char| c.is_whitespace() || c == '-' || c == '|').filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase()).collect::<Vec<String>>();
+        let strong = words.iter().any(|word| STRONG_NOISE.contains(&word.as_str())) || (words.len() > 1 && words.iter().any(|word| word == "official"));
+        strong && words.iter().all(|word| TITLE_NOISE.contains(&word.as_str()))
+    };
+
+    let mut cleaned = String::new();
+    let mut rest = title;
+    while let Some(open) = rest.find(['(', '[']) {
+        let close = if rest[open..].starts_with('(') { ')' } else { ']' };
+        let end = match rest[open..].find(close) {
+            Some(end) => open + end,
+            None => break
+        };
+        cleaned.push_str(&rest[..open]);
+        if !is_noise(&rest[open + 1..end]) { cleaned.push_str(&rest[open..=end]); }
+        rest = &rest[end + 1..];
+    }
+    cleaned.push_str(rest);
+
+    // Noise left bare at the end, e.g. "Song HD" or "Song | Official Video". Other words could be part of
+    // the title ("Don't Stop the Music"), so they are only dropped after a separator.
+    let mut words = cleaned.split_whitespace().collect::<Vec<&str>>();
+    while words.len() > 1 {
+        let separator = words.iter().rposition(|word| *word == "|" || *word == "-").filter(|idx| *idx > 0);
+        match (words.last(), separator) {
+            (Some(word), _) if *word == "|" || *word == "-" || BARE_NOISE.contains(&word.to_lowercase().as_str()) => { words.pop(); }
+            (_, Some(idx)) if is_noise(&words[idx + 1..].join(" ")) => words.truncate(idx),
+            _ => break
+        }
+    }
+    words.join(" ")
+}
+
+/// The length of an audio file according to its headers.
+pub fn read_duration(path: &Path) -> Option<Duration> {
+    let tagged_file = lofty::read_from_path(path).ok()?;
+    Some(tagged_file.properties().duration()).filter(|duration| !duration.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::clean_title;
+
+    #[test]
+    fn clean_title_strips_noise() {
+        assert_eq!(clean_title("Song (Official Video)"), "Song");
+        assert_eq!(clean_title("Song [Lyrics]"), "Song");
+        assert_eq!(clean_title("Song HD"), "Song");
+        assert_eq!(clean_title("Song (feat. X) [Official Music Video] [4K]"), "Song (feat. X)");
+        assert_eq!(clean_title("Song | Official Video"), "Song");
+        assert_eq!(clean_title("Song (Official Lyric Video) - HQ"), "Song");
+    }
+
+    #[test]
+    fn clean_title_keeps_title_words() {
+        assert_eq!(clean_title("Don't Stop the Music"), "Don't Stop the Music");
+        assert_eq!(clean_title("Home Video"), "Home Video");
+        assert_eq!(clean_title("Taylor's Version"), "Taylor's Version");
+        assert_eq!(clean_title("Stay With"), "Stay With");
+        assert_eq!(clean_title("The Clip"), "The Clip");
+        assert_eq!(clean_title("Song (Live)"), "Song (Live)");
+        assert_eq!(clean_title("Song (Remastered 2011) (Official Audio)"), "Song (Remastered 2011)");
+        assert_eq!(clean_title("HD"), "HD");
+        assert_eq!(clean_title("Madonna - Music"), "Madonna - Music");
+        assert_eq!(clean_title("Artist - Video"), "Artist - Video");
+        assert_eq!(clean_title("Song (Music)"), "Song (Music)");
+        assert_eq!(clean_title("Clip - Official"), "Clip - Official");
+    }
+}